@@ -0,0 +1,256 @@
+use std::{collections::HashMap, net::SocketAddr, path::Path, fs};
+
+use git2::Repository;
+
+use crate::{config::{self, Config, ListenerConfig}, errors::{Result, GititError}};
+
+/// Checks that only need the config itself. These are also run before a
+/// reloaded config is accepted.
+pub(crate) fn validate(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let listeners = config.server.listeners();
+    if listeners.is_empty() {
+        errors.push("server: no listeners configured, set server.address or add a [[server.listen]]".to_owned());
+    }
+    for listener in &listeners {
+        if let Err(e) = check_listener(listener) {
+            errors.push(format!("server: {}", e));
+        }
+    }
+
+    for (name, threshold) in [("rename_threshold", config.diff.rename_threshold), ("copy_threshold", config.diff.copy_threshold)] {
+        if threshold > 100 {
+            errors.push(format!("diff.{}: {} is not a percentage between 0 and 100", name, threshold));
+        }
+    }
+
+    if config.web.search_max_results == 0 {
+        errors.push("web.search_max_results: must be at least 1".to_owned());
+    }
+
+    let mut seen = HashMap::<String, &str>::new();
+    let mut slugs = config.repos.keys().collect::<Vec<_>>();
+    slugs.sort();
+    for slug in slugs {
+        let repo = &config.repos[slug];
+        if let Err(e) = check_slug(slug) {
+            errors.push(format!("repos.{:?}: {}", slug, e));
+        }
+        if let Some(other) = seen.insert(slug.to_lowercase(), slug) {
+            errors.push(format!("repos.{:?}: slug only differs in case from {:?}", slug, other));
+        }
+        if let Err(e) = check_url(&repo.url) {
+            errors.push(format!("repos.{:?}.url: {}", slug, e));
+        }
+        for url in &repo.clone_urls {
+            if let Err(e) = check_url(url) {
+                errors.push(format!("repos.{:?}.clone_urls: {}", slug, e));
+            }
+        }
+        if let Some(homepage) = &repo.homepage {
+            if let Err(e) = url::Url::parse(homepage) {
+                errors.push(format!("repos.{:?}.homepage: {:?} is not a valid URL: {}", slug, homepage, e));
+            }
+        }
+    }
+
+    errors
+}
+
+fn check_listener(listener: &ListenerConfig) -> std::result::Result<(), String> {
+    match (&listener.address, &listener.unix) {
+        (Some(address), None) => {
+            if let Err(e) = address.parse::<SocketAddr>() {
+                return Err(format!("{:?} is not a valid socket address: {}", address, e));
+            }
+            if listener.mode.is_some() {
+                return Err(format!("mode only applies to unix sockets ({})", address));
+            }
+        }
+        (None, Some(path)) => {
+            if listener.tls.is_some() {
+                return Err(format!("tls is not supported on unix sockets ({:?})", path));
+            }
+        }
+        _ => return Err("each listener needs exactly one of address or unix".to_owned()),
+    }
+    Ok(())
+}
+
+/// Top-level paths that are taken by gitit's own pages.
+const RESERVED_SLUGS: &[&str] = &["assets", "search"];
+
+fn check_slug(slug: &str) -> std::result::Result<(), String> {
+    if slug.is_empty() {
+        return Err("slug is empty".to_owned());
+    }
+    if RESERVED_SLUGS.contains(&slug) {
+        return Err(format!("{:?} is reserved", slug));
+    }
+    for component in slug.split('/') {
+        if component.is_empty() || component == "." || component == ".." {
+            return Err(format!("{:?} is not a safe path component", component));
+        }
+        if component.starts_with('.') || component.starts_with('-') {
+            return Err(format!("{:?} must not start with '.' or '-'", component));
+        }
+        if let Some(c) = component.chars().find(|c| c.is_control() || matches!(c, '\\' | ':' | '%' | '?' | '#')) {
+            return Err(format!("{:?} contains the disallowed character {:?}", component, c));
+        }
+    }
+    Ok(())
+}
+
+/// Schemes that git can fetch over.
+const URL_SCHEMES: &[&str] = &["http", "https", "git", "ssh", "file"];
+
+fn check_url(url: &str) -> std::result::Result<(), String> {
+    if url.contains("://") {
+        let parsed = url::Url::parse(url).map_err(|e| format!("{:?} is not a valid URL: {}", url, e))?;
+        if !URL_SCHEMES.contains(&parsed.scheme()) {
+            return Err(format!("{:?} uses the unsupported scheme {:?}, expected one of {}", url, parsed.scheme(), URL_SCHEMES.join(", ")));
+        }
+        Ok(())
+    } else if is_scp_like(url) {
+        // e.g. `git@github.com:user/repo.git`
+        Ok(())
+    } else if url.is_empty() {
+        Err("URL is empty".to_owned())
+    } else if Path::new(url).exists() {
+        // Like git, anything else is a path on disk.
+        Ok(())
+    } else {
+        Err(format!("{:?} is neither a URL, host:path nor an existing path", url))
+    }
+}
+
+/// Whether `url` is in git's scp-like `[user@]host:path` form.
+fn is_scp_like(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((host, path)) => !host.is_empty() && !host.contains('/') && !path.is_empty() && !path.starts_with("//"),
+        None => false,
+    }
+}
+
+/// Checks that the certificate and key of each TLS listener, and the keys
+/// used to verify signatures, can be read.
+fn check_credentials(config: &Config, errors: &mut Vec<String>) {
+    for listener in config.server.listeners() {
+        if let Some(tls) = &listener.tls {
+            for path in [&tls.cert, &tls.key] {
+                if let Err(e) = fs::File::open(path) {
+                    errors.push(format!("server: cannot read {:?}: {}", path, e));
+                }
+            }
+        }
+    }
+    for path in config.signatures.gpg_keyrings.iter().chain(&config.signatures.ssh_allowed_signers) {
+        if let Err(e) = fs::File::open(path) {
+            errors.push(format!("signatures: cannot read {:?}: {}", path, e));
+        }
+    }
+}
+
+/// Checks that need to look at the mirrors on disk.
+fn check_mirrors(config: &Config, errors: &mut Vec<String>, warnings: &mut Vec<String>) {
+    let mut slugs = config.repos.keys().collect::<Vec<_>>();
+    slugs.sort();
+    for slug in slugs {
+        let repo_config = &config.repos[slug];
+        let path = config.repo_path(slug, repo_config);
+        if !path.exists() {
+            warnings.push(format!("repos.{:?}: {:?} has not been cloned yet, run `gitit update-repos`", slug, path));
+            continue;
+        }
+        let repo = match Repository::open_bare(&path) {
+            Ok(repo) => repo,
+            Err(e) => {
+                errors.push(format!("repos.{:?}: {:?} is not a bare git repository: {}", slug, path, e.message()));
+                continue;
+            }
+        };
+        if repo.find_reference(&format!("refs/heads/{}", repo_config.head)).is_err() {
+            errors.push(format!("repos.{:?}.head: branch {:?} does not exist in the mirror", slug, repo_config.head));
+        }
+    }
+}
+
+/// Prints the line a TOML parse error points at, with a marker under the
+/// offending column.
+fn print_toml_context(path: &Path, error: &toml::de::Error) {
+    let (line, col) = match error.line_col() {
+        Some(pos) => pos,
+        None => return,
+    };
+    if let Some(text) = fs::read_to_string(path).ok().and_then(|s| s.lines().nth(line).map(|l| l.to_owned())) {
+        eprintln!("  {:>4} | {}", line + 1, text);
+        eprintln!("       | {}^", " ".repeat(col));
+    }
+}
+
+/// Prints every problem with the config at `path` to stderr. The error
+/// returned has already been reported, so callers only need to exit.
+pub(crate) fn check_config(path: &Path) -> Result<()> {
+    let config = match config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            // The error may come from an included file rather than `path`.
+            if let GititError::TomlError { path, source } = &e {
+                print_toml_context(path, source);
+            }
+            return Err(e);
+        }
+    };
+
+    let mut errors = validate(&config);
+    let mut warnings = Vec::new();
+    check_credentials(&config, &mut errors);
+    check_mirrors(&config, &mut errors, &mut warnings);
+
+    for warning in &warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for error in &errors {
+        eprintln!("error: {}", error);
+    }
+
+    if errors.is_empty() {
+        println!("{:?} is valid ({} repos)", path, config.repos.len());
+        Ok(())
+    } else {
+        eprintln!("{} error(s) in {:?}", errors.len(), path);
+        Err(GititError::InvalidConfig(format!("{} error(s) in {:?}", errors.len(), path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_url;
+
+    #[test]
+    fn urls_need_a_scheme_git_can_fetch() {
+        assert!(check_url("https://github.com/ashhhleyyy/gitit.git").is_ok());
+        assert!(check_url("git://example.com/repo.git").is_ok());
+        assert!(check_url("ssh://git@example.com/repo.git").is_ok());
+        assert!(check_url("mailto://someone@example.com").is_err());
+        assert!(check_url("javascript://alert(1)").is_err());
+    }
+
+    #[test]
+    fn scp_like_urls_need_a_host_and_a_path() {
+        assert!(check_url("git@github.com:ashhhleyyy/gitit.git").is_ok());
+        assert!(check_url("example.com:repo.git").is_ok());
+        assert!(check_url(":repo.git").is_err());
+        assert!(check_url("git@github.com:").is_err());
+    }
+
+    #[test]
+    fn local_paths_must_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_url(&dir.path().to_string_lossy()).is_ok());
+        assert!(check_url(&dir.path().join("missing.git").to_string_lossy()).is_err());
+        assert!(check_url("").is_err());
+    }
+}
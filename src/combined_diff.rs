@@ -0,0 +1,296 @@
+use std::{collections::BTreeSet, path::{Path, PathBuf}};
+
+use git2::{Commit, DiffOptions, Patch, Repository};
+
+use crate::errors::Result;
+
+const CONTEXT: usize = 3;
+
+/// One line of a combined diff: either a line of the merge result, or a line
+/// of one or more parents that the result dropped.
+struct Row {
+    /// One marker per parent, `+`, `-` or a space.
+    columns: Vec<char>,
+    in_result: bool,
+    text: String,
+}
+
+impl Row {
+    fn in_parent(&self, parent: usize) -> bool {
+        if self.in_result { self.columns[parent] == ' ' } else { self.columns[parent] == '-' }
+    }
+}
+
+/// Builds a `git diff --cc` style combined diff of a merge: only files that
+/// differ from every parent are listed, and only hunks where the result
+/// differs from every parent are shown.
+pub(crate) fn combined_diff(repo: &Repository, commit: &Commit) -> Result<String> {
+    let tree = commit.tree()?;
+    let parents = commit.parents().collect::<Vec<_>>();
+
+    let mut changed: Option<BTreeSet<PathBuf>> = None;
+    for parent in &parents {
+        let diff = repo.diff_tree_to_tree(Some(&parent.tree()?), Some(&tree), Some(&mut DiffOptions::new()))?;
+        let paths = diff.deltas()
+            .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()).map(Path::to_owned))
+            .collect::<BTreeSet<_>>();
+        changed = Some(match changed {
+            Some(changed) => changed.intersection(&paths).cloned().collect(),
+            None => paths,
+        });
+    }
+
+    let mut output = String::new();
+    for path in changed.unwrap_or_default() {
+        let result = blob_content(repo, &tree, &path)?;
+        let parent_contents = parents.iter()
+            .map(|parent| blob_content(repo, &parent.tree()?, &path))
+            .collect::<Result<Vec<_>>>()?;
+
+        let is_binary = |content: &[u8]| content.contains(&0);
+        if is_binary(&result) || parent_contents.iter().any(|c| is_binary(c)) {
+            output.push_str(&format!("diff --cc {}\nBinary files differ\n", path.display()));
+            continue;
+        }
+        let rows = combine(&result, &parent_contents)?;
+        let mut hunks = String::new();
+        format_hunks(&rows, parents.len(), &mut hunks);
+        // Files whose changes all came from one side have nothing to show.
+        if !hunks.is_empty() {
+            output.push_str(&format!("diff --cc {}\n", path.display()));
+            output.push_str(&hunks);
+        }
+    }
+    Ok(output)
+}
+
+fn blob_content(repo: &Repository, tree: &git2::Tree, path: &Path) -> Result<Vec<u8>> {
+    match tree.get_path(path) {
+        Ok(entry) if entry.kind() == Some(git2::ObjectType::Blob) => Ok(repo.find_blob(entry.id())?.content().to_owned()),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn combine(result: &[u8], parents: &[Vec<u8>]) -> Result<Vec<Row>> {
+    let result_lines = String::from_utf8_lossy(result).lines().map(str::to_owned).collect::<Vec<_>>();
+    let n = result_lines.len();
+
+    // For each parent: which result lines it lacks, and which of its own lines
+    // were dropped before each result line (index `n` is the end of the file).
+    let mut added = vec![vec![false; n]; parents.len()];
+    let mut lost: Vec<Vec<Vec<String>>> = vec![vec![Vec::new(); n + 1]; parents.len()];
+    for (i, parent) in parents.iter().enumerate() {
+        let mut options = DiffOptions::new();
+        options.context_lines(0);
+        let patch = Patch::from_buffers(parent, None, result, None, Some(&mut options))?;
+        for h in 0..patch.num_hunks() {
+            let (hunk, lines) = patch.hunk(h)?;
+            // With no new lines, `new_start` is the line before the deletion.
+            let position = if hunk.new_lines() == 0 { hunk.new_start() } else { hunk.new_start() - 1 } as usize;
+            for l in 0..lines {
+                let line = patch.line_in_hunk(h, l)?;
+                match line.origin() {
+                    '+' => if let Some(k) = line.new_lineno() { added[i][k as usize - 1] = true },
+                    '-' => {
+                        let text = String::from_utf8_lossy(line.content()).trim_end_matches('\n').to_owned();
+                        lost[i][position.min(n)].push(text);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let mut rows = Vec::new();
+    for k in 0..=n {
+        // Lines dropped from several parents are shown once, with a `-` in
+        // each of their columns.
+        let start = rows.len();
+        for (i, lost) in lost.iter().enumerate() {
+            for text in &lost[k] {
+                let existing = rows[start..].iter_mut()
+                    .find(|row: &&mut Row| row.text == *text && row.columns[i] == ' ');
+                match existing {
+                    Some(row) => row.columns[i] = '-',
+                    None => {
+                        let mut columns = vec![' '; parents.len()];
+                        columns[i] = '-';
+                        rows.push(Row { columns, in_result: false, text: text.clone() });
+                    }
+                }
+            }
+        }
+        if k < n {
+            let columns = added.iter().map(|added| if added[k] { '+' } else { ' ' }).collect();
+            rows.push(Row { columns, in_result: true, text: result_lines[k].clone() });
+        }
+    }
+    Ok(rows)
+}
+
+fn format_hunks(rows: &[Row], parents: usize, output: &mut String) {
+    let changed = |row: &Row| row.columns.iter().any(|&c| c != ' ');
+    let changed_rows = rows.iter()
+        .enumerate()
+        .filter(|(_, row)| changed(row))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    // Like git, changes less than `CONTEXT` rows apart are considered
+    // together, and a group is only shown if the result disagrees with every
+    // parent somewhere in it. A group whose changes all come from the same
+    // parents was simply taken from one side of the merge.
+    let mut shown = vec![false; rows.len()];
+    let mut start = 0;
+    while start < changed_rows.len() {
+        let mut end = start + 1;
+        while end < changed_rows.len() && changed_rows[end] - changed_rows[end - 1] <= CONTEXT {
+            end += 1;
+        }
+        let group = &changed_rows[start..end];
+        let sides = |&i: &usize| rows[i].columns.iter().map(|&c| c != ' ').collect::<Vec<_>>();
+        let first = sides(&group[0]);
+        let interesting = group.iter().any(|i| sides(i) != first) || first.iter().all(|&side| side);
+        if interesting {
+            let from = group[0].saturating_sub(CONTEXT);
+            let to = (group[group.len() - 1] + CONTEXT + 1).min(rows.len());
+            shown[from..to].iter_mut().for_each(|s| *s = true);
+        }
+        start = end;
+    }
+
+    // Line numbers (1-based) of the next row in each parent and the result.
+    let mut parent_lines = vec![1; parents];
+    let mut result_line = 1;
+    let mut i = 0;
+    while i < rows.len() {
+        if !shown[i] {
+            advance(&rows[i], &mut parent_lines, &mut result_line);
+            i += 1;
+            continue;
+        }
+        let end = (i..rows.len()).find(|&j| !shown[j]).unwrap_or(rows.len());
+        let hunk = &rows[i..end];
+
+        let marker = "@".repeat(parents + 1);
+        output.push_str(&marker);
+        for (p, start) in parent_lines.iter().enumerate() {
+            let count = hunk.iter().filter(|row| row.in_parent(p)).count();
+            output.push_str(&format!(" -{},{}", start, count));
+        }
+        let count = hunk.iter().filter(|row| row.in_result).count();
+        output.push_str(&format!(" +{},{} {}\n", result_line, count, marker));
+
+        for row in hunk {
+            output.extend(row.columns.iter());
+            output.push_str(&row.text);
+            output.push('\n');
+            advance(row, &mut parent_lines, &mut result_line);
+        }
+        i = end;
+    }
+}
+
+fn advance(row: &Row, parent_lines: &mut [usize], result_line: &mut usize) {
+    for (p, line) in parent_lines.iter_mut().enumerate() {
+        if row.in_parent(p) {
+            *line += 1;
+        }
+    }
+    if row.in_result {
+        *result_line += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cc(result: &str, parents: &[&str]) -> String {
+        let parents = parents.iter().map(|p| p.as_bytes().to_vec()).collect::<Vec<_>>();
+        let rows = combine(result.as_bytes(), &parents).unwrap();
+        let mut output = String::new();
+        format_hunks(&rows, parents.len(), &mut output);
+        output
+    }
+
+    fn lines(changes: &[(usize, &str)]) -> String {
+        (1..=12)
+            .map(|n| changes.iter().find(|(line, _)| *line == n).map(|(_, text)| text.to_string()).unwrap_or(format!("l{}", n)))
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn resolved_conflicts_are_shown_against_both_parents() {
+        let ours = lines(&[(2, "M2"), (6, "M6"), (11, "M11")]);
+        let theirs = lines(&[(2, "S2"), (11, "S11")]);
+        let result = lines(&[(2, "R2"), (6, "M6"), (11, "R11")]);
+        // The same as `git show --cc`, minus the function context after `@@@`.
+        assert_eq!(cc(&result, &[&ours, &theirs]), "\
+@@@ -1,5 -1,5 +1,5 @@@
+  l1
+- M2
+ -S2
+++R2
+  l3
+  l4
+  l5
+@@@ -8,5 -8,5 +8,5 @@@
+  l8
+  l9
+  l10
+- M11
+ -S11
+++R11
+  l12
+");
+    }
+
+    #[test]
+    fn changes_taken_from_one_side_are_hidden() {
+        let ours = lines(&[(6, "M6")]);
+        let theirs = lines(&[(2, "S2")]);
+        let result = lines(&[(2, "S2"), (6, "M6")]);
+        assert_eq!(cc(&result, &[&ours, &theirs]), "");
+    }
+
+    #[test]
+    fn nearby_changes_from_different_sides_are_shown() {
+        let ours = lines(&[(2, "M2")]);
+        let theirs = lines(&[(4, "S4")]);
+        let result = lines(&[(2, "M2"), (4, "S4")]);
+        assert_eq!(cc(&result, &[&ours, &theirs]), "\
+@@@ -1,7 -1,7 +1,7 @@@
+  l1
+ -l2
+ +M2
+  l3
+- l4
++ S4
+  l5
+  l6
+  l7
+");
+    }
+
+    #[test]
+    fn lines_dropped_from_both_parents_are_shown_once() {
+        assert_eq!(cc("a\nc\n", &["a\nb\nc\n", "a\nb\nc\n"]), "\
+@@@ -1,3 -1,3 +1,2 @@@
+  a
+--b
+  c
+");
+    }
+
+    #[test]
+    fn lines_added_at_the_end() {
+        assert_eq!(cc("a\nb\n", &["a\n", "a\nc\n"]), "\
+@@@ -1,1 -1,2 +1,2 @@@
+  a
+ -c
+++b
+");
+    }
+}
@@ -1,38 +1,548 @@
-use std::{path::Path, fs, collections::HashMap};
+use std::{path::{Path, PathBuf}, fs, collections::HashMap, ffi::OsString, sync::{Arc, RwLock}};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use toml::{Value, value::Table};
 
 use crate::errors::{Result, GititError};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     pub server: ListenConfig,
+    #[serde(default)]
+    pub update: UpdateConfig,
+    #[serde(default)]
+    pub web: WebConfig,
+    #[serde(default)]
+    pub diff: DiffConfig,
+    #[serde(default)]
+    pub signatures: SignatureConfig,
+    /// Directory that mirrors are stored in. Relative paths are resolved
+    /// against the directory containing the config file.
+    #[serde(default = "default_storage_dir")]
+    pub storage_dir: PathBuf,
     pub repos: HashMap<String, RepoConfig>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ListenConfig {
-    pub address: String,
+    /// Shorthand for a single plain TCP listener.
+    pub address: Option<String>,
+    #[serde(default)]
+    pub listen: Vec<ListenerConfig>,
+    /// URL path prefix that gitit is served under, e.g. `/git`. Empty when
+    /// served from the root.
+    #[serde(default)]
+    pub base_path: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl ListenConfig {
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        let mut listeners = self.listen.clone();
+        if let Some(address) = &self.address {
+            listeners.insert(0, ListenerConfig {
+                address: Some(address.clone()),
+                unix: None,
+                mode: None,
+                tls: None,
+            });
+        }
+        listeners
+    }
+}
+
+/// A single socket to serve on: either a TCP `address` (optionally with
+/// `tls`) or a `unix` socket path.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListenerConfig {
+    pub address: Option<String>,
+    pub unix: Option<PathBuf>,
+    /// Permissions for the Unix socket, e.g. `0o660`.
+    pub mode: Option<u32>,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain.
+    pub cert: PathBuf,
+    /// PEM file containing the private key.
+    pub key: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebConfig {
+    /// Number of commits to show per page of history.
+    #[serde(default = "default_log_page_size")]
+    pub log_page_size: usize,
+    /// Stop a code search after this many matching lines.
+    #[serde(default = "default_search_max_results")]
+    pub search_max_results: usize,
+    /// Stop a code search after this many seconds.
+    #[serde(default = "default_search_timeout_secs")]
+    pub search_timeout_secs: u64,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            log_page_size: default_log_page_size(),
+            search_max_results: default_search_max_results(),
+            search_timeout_secs: default_search_timeout_secs(),
+        }
+    }
+}
+
+/// How diffs are computed for the commit, compare and raw diff views.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiffConfig {
+    /// Show a moved file as a rename rather than a delete and an add.
+    #[serde(default = "default_true")]
+    pub renames: bool,
+    /// Also look for new files copied from files changed in the same diff.
+    #[serde(default)]
+    pub copies: bool,
+    /// How similar, in percent, a file has to be to count as renamed.
+    #[serde(default = "default_similarity_threshold")]
+    pub rename_threshold: u16,
+    /// How similar, in percent, a file has to be to count as copied.
+    #[serde(default = "default_similarity_threshold")]
+    pub copy_threshold: u16,
+}
+
+impl Default for DiffConfig {
+    fn default() -> Self {
+        Self {
+            renames: default_true(),
+            copies: false,
+            rename_threshold: default_similarity_threshold(),
+            copy_threshold: default_similarity_threshold(),
+        }
+    }
+}
+
+/// Keys trusted when verifying commit and tag signatures. Relative paths are
+/// resolved against the directory containing the config file.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SignatureConfig {
+    /// OpenPGP keyrings, as written by `gpg --export`.
+    #[serde(default)]
+    pub gpg_keyrings: Vec<PathBuf>,
+    /// An `allowed_signers` file for SSH signatures, in the format used by
+    /// git's `gpg.ssh.allowedSignersFile`.
+    pub ssh_allowed_signers: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UpdateConfig {
+    /// Run a connectivity and object integrity check after every fetch.
+    #[serde(default)]
+    pub verify: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RepoConfig {
     pub url: String,
     pub title: String,
     #[serde(default = "default_head")]
     pub head: String,
+    /// Where to store this mirror, relative to `storage_dir`. Defaults to
+    /// `<slug>.git`.
+    pub path: Option<PathBuf>,
+    /// Section to list this repo under. Defaults to the part of the slug
+    /// before the first `/`, if there is one.
+    pub group: Option<String>,
+    /// Leave this repo off the list page. It can still be browsed directly.
+    #[serde(default)]
+    pub hidden: bool,
+    /// Short summary of the project. Falls back to the mirror's `description`
+    /// file when unset.
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub maintainers: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Extra URLs to advertise for cloning, alongside the upstream URL.
+    #[serde(default)]
+    pub clone_urls: Vec<String>,
+}
+
+impl RepoConfig {
+    pub fn group<'a>(&'a self, slug: &'a str) -> Option<&'a str> {
+        self.group.as_deref()
+            .or_else(|| slug.split_once('/').map(|(group, _)| group))
+    }
+}
+
+impl Config {
+    pub fn repo_path(&self, slug: &str, repo: &RepoConfig) -> PathBuf {
+        match &repo.path {
+            Some(path) => self.storage_dir.join(path),
+            None => self.storage_dir.join(format!("{}.git", slug)),
+        }
+    }
+}
+
+/// The active configuration, shared between request handlers and swapped out
+/// wholesale when the config file is reloaded.
+#[derive(Clone, Debug)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn load(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
 }
 
 fn default_head() -> String {
     "main".to_owned()
 }
 
-pub(super) fn load() -> Result<Config> {
-    let path = Path::new("gitit.toml");
-    if path.exists() {
-        let content = fs::read_to_string(path)?;
-        Ok(toml::from_str(&content)?)
+fn default_log_page_size() -> usize {
+    50
+}
+
+fn default_search_max_results() -> usize {
+    200
+}
+
+fn default_search_timeout_secs() -> u64 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Git's default for both `-M` and `-C`.
+fn default_similarity_threshold() -> u16 {
+    50
+}
+
+fn default_storage_dir() -> PathBuf {
+    PathBuf::from("repos")
+}
+
+/// Prefix for environment variables that override config values. Nested keys
+/// are separated by a double underscore, e.g. `GITIT_SERVER__ADDRESS`.
+const ENV_PREFIX: &str = "GITIT_";
+
+fn read_toml(path: &Path) -> Result<Value> {
+    let content = fs::read_to_string(path)?;
+    toml::from_str(&content)
+        .map_err(|source| GititError::TomlError { path: path.to_owned(), source })
+}
+
+/// Returns the files matched by the `include` patterns of the main config
+/// file, in the order they are applied.
+fn includes(path: &Path, root: &Value) -> Result<Vec<PathBuf>> {
+    let patterns = match root.get("include") {
+        Some(Value::Array(patterns)) => patterns,
+        Some(_) => return Err(GititError::InvalidConfig("include must be an array of glob patterns".to_owned())),
+        None => return Ok(vec![]),
+    };
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let pattern = pattern.as_str()
+            .ok_or_else(|| GititError::InvalidConfig("include must be an array of glob patterns".to_owned()))?;
+        let full_pattern = base.join(pattern);
+        let paths = glob::glob(&full_pattern.to_string_lossy())
+            .map_err(|e| GititError::InvalidConfig(format!("invalid include pattern {:?}: {}", pattern, e)))?;
+        let mut matched = paths.collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| GititError::IOError(e.into()))?;
+        matched.sort();
+        files.extend(matched);
+    }
+    Ok(files)
+}
+
+/// All of the files that make up the config, starting with the main file.
+pub(crate) fn sources(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![path.to_owned()];
+    files.extend(includes(path, &read_toml(path)?)?);
+    Ok(files)
+}
+
+/// Recursively merges `overlay` into `base`. Tables are merged key by key,
+/// anything else in `overlay` replaces what was in `base`.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Applies the `GITIT_*` variables in `vars` on top of `root`. Other
+/// variables are ignored, even if they aren't valid UTF-8.
+fn apply_env(root: &mut Value, vars: impl Iterator<Item = (OsString, OsString)>) -> Result<()> {
+    let defaults = defaults()?;
+    for (name, raw) in vars {
+        if !name.to_string_lossy().starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let (name, raw) = match (name.into_string(), raw.into_string()) {
+            (Ok(name), Ok(raw)) => (name, raw),
+            (Ok(name), Err(_)) => return Err(GititError::InvalidConfig(format!("{} is not valid UTF-8", name))),
+            (Err(name), _) => return Err(GititError::InvalidConfig(format!("{} is not valid UTF-8", name.to_string_lossy()))),
+        };
+        let key = match name.strip_prefix(ENV_PREFIX) {
+            Some(key) if key != "CONFIG" => key.to_lowercase(),
+            _ => continue,
+        };
+        let segments = key.split("__").collect::<Vec<_>>();
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err(GititError::InvalidConfig(format!("invalid override variable {}", name)));
+        }
+        let keys = resolve_keys(root, &name, &segments)?;
+        let segments = keys.iter().map(String::as_str).collect::<Vec<_>>();
+        let existing = lookup(root, &segments)
+            .or_else(|| lookup(&defaults.config, &segments))
+            .or_else(|| match segments.as_slice() {
+                ["repos", _, rest @ ..] => lookup(&defaults.repo, rest),
+                _ => None,
+            });
+        let value = env_value(&name, raw, existing)?;
+
+        let mut overlay = value;
+        for segment in segments.iter().rev() {
+            let mut table = Table::new();
+            table.insert(segment.to_string(), overlay);
+            overlay = Value::Table(table);
+        }
+        merge(root, overlay);
+    }
+    Ok(())
+}
+
+/// How a key is spelled in an environment variable, which can only hold
+/// letters, digits and `_`.
+fn env_key(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+/// Finds the keys in `root` that the segments of an override name, so slugs
+/// like `My-Repo` can be set with `GITIT_REPOS__MY_REPO__*`. Segments with no
+/// such key name a new setting and are taken as they are.
+fn resolve_keys(root: &Value, name: &str, segments: &[&str]) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut table = root.as_table();
+    for segment in segments {
+        let matching = table
+            .map(|table| table.keys().filter(|key| env_key(key) == *segment).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let key = match matching.as_slice() {
+            [] => segment.to_string(),
+            [key] => key.to_string(),
+            _ if matching.iter().any(|key| key == segment) => segment.to_string(),
+            _ => {
+                let matching = matching.iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>();
+                return Err(GititError::InvalidConfig(format!("{} could mean any of {}", name, matching.join(", "))));
+            }
+        };
+        table = table.and_then(|table| table.get(&key)).and_then(Value::as_table);
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Default values of every setting, used to tell the type of a setting that
+/// isn't in the config file.
+struct Defaults {
+    config: Value,
+    repo: Value,
+}
+
+fn defaults() -> Result<Defaults> {
+    let invalid = |e: &dyn std::fmt::Display| GititError::InvalidConfig(e.to_string());
+    let config = toml::from_str::<Config>("[server]\n[repos]").map_err(|e| invalid(&e))?;
+    let repo = toml::from_str::<RepoConfig>("url = ''\ntitle = ''").map_err(|e| invalid(&e))?;
+    Ok(Defaults {
+        config: Value::try_from(config).map_err(|e| invalid(&e))?,
+        repo: Value::try_from(repo).map_err(|e| invalid(&e))?,
+    })
+}
+
+fn lookup<'a>(value: &'a Value, segments: &[&str]) -> Option<&'a Value> {
+    segments.iter().try_fold(value, |value, segment| value.get(segment))
+}
+
+/// Parses an override as the type of the setting it replaces. Strings, and
+/// settings whose type isn't known, take the value as-is.
+fn env_value(name: &str, raw: String, existing: Option<&Value>) -> Result<Value> {
+    let existing = match existing {
+        None | Some(Value::String(_)) => return Ok(Value::String(raw)),
+        Some(existing) => existing,
+    };
+    let value = toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"));
+    match (value, existing) {
+        (Some(value), existing) if value.same_type(existing) => Ok(value),
+        (Some(Value::Integer(n)), Value::Float(_)) => Ok(Value::Float(n as f64)),
+        _ => Err(GititError::InvalidConfig(format!("{}: {:?} is not a valid {}", name, raw, existing.type_str()))),
+    }
+}
+
+/// Turns `git/`, `/git/` and `/git` into `/git`, and `/` into an empty string.
+fn normalize_base_path(base_path: &str) -> String {
+    let trimmed = base_path.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
     } else {
-        Err(GititError::MissingConfig)
+        format!("/{}", trimmed)
+    }
+}
+
+/// Loads the config file at `path`, then applies each included file in turn,
+/// and finally any `GITIT_*` environment overrides. Later layers win.
+pub(crate) fn load(path: &Path) -> Result<Config> {
+    if !path.exists() {
+        return Err(GititError::MissingConfig);
+    }
+
+    let mut root = read_toml(path)?;
+    for include in includes(path, &root)? {
+        let overlay = read_toml(&include)?;
+        if overlay.get("include").is_some() {
+            tracing::warn!("{:?}: nested includes are ignored", include);
+        }
+        merge(&mut root, overlay);
+    }
+    apply_env(&mut root, std::env::vars_os())?;
+
+    let mut config: Config = root.try_into()
+        .map_err(|source| GititError::TomlError { path: path.to_owned(), source })?;
+    config.server.base_path = normalize_base_path(&config.server.base_path);
+    // Relative paths are resolved against an absolute directory, since some
+    // of them are handed to tools like gpgv that treat bare names specially.
+    let absolute = std::path::absolute(path)?;
+    if let Some(base) = absolute.parent() {
+        config.storage_dir = base.join(&config.storage_dir);
+        for listener in &mut config.server.listen {
+            if let Some(unix) = &mut listener.unix {
+                *unix = base.join(&*unix);
+            }
+            if let Some(tls) = &mut listener.tls {
+                tls.cert = base.join(&tls.cert);
+                tls.key = base.join(&tls.key);
+            }
+        }
+        for keyring in &mut config.signatures.gpg_keyrings {
+            *keyring = base.join(&*keyring);
+        }
+        if let Some(allowed_signers) = &mut config.signatures.ssh_allowed_signers {
+            *allowed_signers = base.join(&*allowed_signers);
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, fs, os::unix::ffi::OsStringExt};
+
+    use toml::Value;
+
+    use super::{apply_env, load};
+
+    fn apply(toml: &str, vars: &[(&str, &str)]) -> crate::errors::Result<Value> {
+        let mut root = toml::from_str(toml).unwrap();
+        apply_env(&mut root, vars.iter().map(|(name, value)| (name.into(), value.into())))?;
+        Ok(root)
+    }
+
+    #[test]
+    fn later_includes_win() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        fs::write(dir.path().join("gitit.toml"), r#"
+            include = ["conf.d/*.toml"]
+            [server]
+            address = "127.0.0.1:8000"
+            [web]
+            log_page_size = 5
+            [repos.a]
+            url = "https://example.com/a.git"
+            title = "A"
+        "#).unwrap();
+        fs::write(dir.path().join("conf.d/20-b.toml"), "[web]\nlog_page_size = 20\n").unwrap();
+        fs::write(dir.path().join("conf.d/10-a.toml"), r#"
+            [web]
+            log_page_size = 10
+            [repos.b]
+            url = "https://example.com/b.git"
+            title = "B"
+        "#).unwrap();
+
+        let config = load(&dir.path().join("gitit.toml")).unwrap();
+        assert_eq!(config.web.log_page_size, 20);
+        assert_eq!(config.server.address.as_deref(), Some("127.0.0.1:8000"));
+        assert_eq!(config.repos.len(), 2);
+    }
+
+    #[test]
+    fn overrides_take_the_type_of_the_setting() {
+        let root = apply("[repos.a]\nurl = ''\ntitle = ''", &[
+            ("GITIT_UPDATE__VERIFY", "true"),
+            ("GITIT_WEB__LOG_PAGE_SIZE", "25"),
+            ("GITIT_REPOS__A__TOPICS", r#"["rust", "git"]"#),
+            ("GITIT_REPOS__A__TITLE", "true"),
+        ]).unwrap();
+        assert_eq!(root["update"]["verify"], Value::Boolean(true));
+        assert_eq!(root["web"]["log_page_size"], Value::Integer(25));
+        assert_eq!(root["repos"]["a"]["topics"], Value::Array(vec!["rust".into(), "git".into()]));
+        assert_eq!(root["repos"]["a"]["title"], Value::String("true".to_owned()));
+    }
+
+    #[test]
+    fn overrides_of_the_wrong_type_are_rejected() {
+        assert!(apply("", &[("GITIT_WEB__LOG_PAGE_SIZE", "many")]).is_err());
+        assert!(apply("", &[("GITIT_UPDATE__VERIFY", "1")]).is_err());
+    }
+
+    #[test]
+    fn override_keys_ignore_case_and_punctuation() {
+        let root = apply("[repos.My-Repo]\nurl = ''\ntitle = ''", &[("GITIT_REPOS__MY_REPO__TITLE", "Mine")]).unwrap();
+        assert_eq!(root["repos"]["My-Repo"]["title"], Value::String("Mine".to_owned()));
+        assert!(root["repos"].get("my_repo").is_none());
+    }
+
+    #[test]
+    fn ambiguous_override_keys_are_rejected() {
+        let toml = "[repos.My-Repo]\nurl = ''\ntitle = ''\n[repos.'my.repo']\nurl = ''\ntitle = ''";
+        assert!(apply(toml, &[("GITIT_REPOS__MY_REPO__TITLE", "Mine")]).is_err());
+        // An exact match is not ambiguous.
+        let toml = "[repos.My-Repo]\nurl = ''\ntitle = ''\n[repos.my_repo]\nurl = ''\ntitle = ''";
+        let root = apply(toml, &[("GITIT_REPOS__MY_REPO__TITLE", "Mine")]).unwrap();
+        assert_eq!(root["repos"]["my_repo"]["title"], Value::String("Mine".to_owned()));
+    }
+
+    #[test]
+    fn non_utf8_variables_are_only_an_error_for_overrides() {
+        let mut root = Value::Table(Default::default());
+        let invalid = OsString::from_vec(vec![0xff]);
+        assert!(apply_env(&mut root, [(invalid.clone(), invalid.clone())].into_iter()).is_ok());
+        assert!(apply_env(&mut root, [("GITIT_SERVER__ADDRESS".into(), invalid)].into_iter()).is_err());
     }
 }
@@ -12,12 +12,26 @@ pub enum GititError {
     Redirect(String),
     #[error("highlighting error: {0}")]
     HighlightingError(#[from] syntect::Error),
-    #[error("missing config")]
+    #[error("missing config file")]
     MissingConfig,
     #[error("io error: {0}")]
     IOError(#[from] std::io::Error),
-    #[error("toml parser error: {0}")]
-    TomlError(#[from] toml::de::Error),
+    #[error("failed to parse {path:?}: {source}")]
+    TomlError { path: std::path::PathBuf, source: toml::de::Error },
+    #[error("server error: {0}")]
+    ServerError(#[from] hyper::Error),
+    #[error("tls error: {0}")]
+    TlsError(#[from] tokio_rustls::rustls::Error),
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("verification failed: {0}")]
+    VerificationFailed(String),
+    #[error("mirror is corrupt: {0}")]
+    CorruptMirror(String),
+    #[error("background task failed: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
+    #[error("search index error: {0}")]
+    IndexError(#[from] tantivy::TantivyError),
 }
 
 impl IntoResponse for GititError {
@@ -28,6 +42,7 @@ impl IntoResponse for GititError {
             GititError::GitError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "git error"),
             GititError::NotFound => (StatusCode::NOT_FOUND, "not found"),
             GititError::HighlightingError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "highlighting error"),
+            GititError::CorruptMirror(_) => (StatusCode::SERVICE_UNAVAILABLE, "mirror failed verification"),
             GititError::Redirect(target) => {
                 return (StatusCode::TEMPORARY_REDIRECT, [(header::LOCATION, target)]).into_response();
             }
@@ -0,0 +1,514 @@
+use std::{collections::{BTreeSet, HashMap}, fs, path::{Path, PathBuf}, sync::{Mutex, OnceLock}};
+
+use git2::{Delta, ObjectType, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use serde::{Deserialize, Serialize};
+use syntect::parsing::SyntaxSet;
+use tantivy::{
+    collector::{Count, TopDocs},
+    directory::MmapDirectory,
+    query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RegexQuery, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, Value, STORED, STRING, TEXT},
+    snippet::SnippetGenerator,
+    Index, IndexReader, IndexWriter, TantivyDocument, TantivyError, Term,
+};
+
+use crate::{config::Config, errors::Result, search::MAX_BLOB_SIZE, utils::{encode_path, templates::format_time}};
+
+/// Heap shared by the index writer's threads.
+const WRITER_MEMORY: usize = 100_000_000;
+
+static READERS: OnceLock<Mutex<HashMap<PathBuf, (Index, IndexReader)>>> = OnceLock::new();
+
+/// The index lives next to the mirrors. Slugs can't start with a dot, so it
+/// can't clash with one of them.
+fn index_dir(config: &Config) -> PathBuf {
+    config.storage_dir.join(".index")
+}
+
+fn schema() -> Schema {
+    let mut builder = Schema::builder();
+    // `kind:repo:path` for files and `kind:repo:oid` for commits, used to
+    // replace documents.
+    builder.add_text_field("id", STRING);
+    builder.add_text_field("kind", STRING | STORED);
+    builder.add_text_field("repo", STRING | STORED);
+    builder.add_text_field("path", STRING | STORED);
+    builder.add_text_field("language", STRING | STORED);
+    builder.add_text_field("content", TEXT | STORED);
+    builder.add_text_field("commit", STORED);
+    builder.add_text_field("author", TEXT | STORED);
+    builder.add_text_field("date", STORED);
+    builder.build()
+}
+
+struct Fields {
+    id: Field,
+    kind: Field,
+    repo: Field,
+    path: Field,
+    language: Field,
+    content: Field,
+    commit: Field,
+    author: Field,
+    date: Field,
+}
+
+impl Fields {
+    fn new(schema: &Schema) -> Result<Self> {
+        Ok(Self {
+            id: schema.get_field("id")?,
+            kind: schema.get_field("kind")?,
+            repo: schema.get_field("repo")?,
+            path: schema.get_field("path")?,
+            language: schema.get_field("language")?,
+            content: schema.get_field("content")?,
+            commit: schema.get_field("commit")?,
+            author: schema.get_field("author")?,
+            date: schema.get_field("date")?,
+        })
+    }
+}
+
+/// What has been indexed of a repo so far. This is kept in the payload of
+/// each index commit, so it can't get out of step with the documents.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct RepoState {
+    /// The tree whose files are indexed.
+    tree: Option<String>,
+    /// Branch and tag tips whose history is indexed.
+    tips: Vec<String>,
+}
+
+/// Keeps the index up to date as mirrors are fetched.
+pub(crate) struct Indexer {
+    writer: IndexWriter,
+    fields: Fields,
+    state: HashMap<String, RepoState>,
+    syntaxes: SyntaxSet,
+}
+
+impl Indexer {
+    pub(crate) fn open(config: &Config) -> Result<Self> {
+        let dir = index_dir(config);
+        fs::create_dir_all(&dir)?;
+        let index = Index::open_or_create(MmapDirectory::open(&dir).map_err(TantivyError::from)?, schema())?;
+        let writer = index.writer(WRITER_MEMORY)?;
+        let fields = Fields::new(&index.schema())?;
+        // An unreadable payload only costs a full reindex.
+        let state = index.load_metas()?.payload
+            .and_then(|payload| serde_json::from_str(&payload).ok())
+            .unwrap_or_default();
+        Ok(Self { writer, fields, state, syntaxes: SyntaxSet::load_defaults_newlines() })
+    }
+
+    /// Drops the documents of repos that are no longer configured.
+    pub(crate) fn retain(&mut self, config: &Config) -> Result<()> {
+        let removed = self.state.keys()
+            .filter(|slug| !config.repos.contains_key(*slug))
+            .cloned()
+            .collect::<Vec<_>>();
+        for slug in removed {
+            self.remove_repo(&slug)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove_repo(&mut self, slug: &str) -> Result<()> {
+        self.state.remove(slug);
+        self.writer.delete_term(Term::from_field_text(self.fields.repo, slug));
+        self.commit()
+    }
+
+    /// Brings the documents of a repo up to date: the files on its `head`
+    /// branch, and the messages of every commit on a branch or tag.
+    pub(crate) fn update_repo(&mut self, slug: &str, head: &str, repo: &Repository) -> Result<()> {
+        tracing::info!("Indexing {}...", slug);
+        let mut state = self.state.get(slug).cloned().unwrap_or_default();
+        let mut result = self.update_files(slug, head, repo, &mut state);
+        if result.is_ok() {
+            result = self.update_commits(slug, repo, &mut state);
+        }
+        if let Err(e) = result {
+            // Don't let half of this repo go in with the next commit.
+            self.writer.rollback()?;
+            return Err(e);
+        }
+        self.state.insert(slug.to_owned(), state);
+        self.commit()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let payload = serde_json::to_string(&self.state).unwrap();
+        let mut commit = self.writer.prepare_commit()?;
+        commit.set_payload(&payload);
+        commit.commit()?;
+        Ok(())
+    }
+
+    fn update_files(&mut self, slug: &str, head: &str, repo: &Repository, state: &mut RepoState) -> Result<()> {
+        let tree = repo.revparse_single(&format!("refs/heads/{}", head))
+            .and_then(|object| object.peel_to_tree())
+            .ok();
+        let old_tree = state.tree.as_deref()
+            .and_then(|id| Oid::from_str(id).ok())
+            .and_then(|id| repo.find_tree(id).ok());
+        if tree.as_ref().map(Tree::id) == old_tree.as_ref().map(Tree::id) {
+            return Ok(());
+        }
+
+        match (&old_tree, &tree) {
+            // Only reindex what changed since last time.
+            (Some(old_tree), Some(tree)) => {
+                let diff = repo.diff_tree_to_tree(Some(old_tree), Some(tree), None)?;
+                for delta in diff.deltas() {
+                    if let Some(path) = delta.old_file().path().and_then(Path::to_str) {
+                        self.writer.delete_term(self.file_id(slug, path));
+                    }
+                    if delta.status() == Delta::Deleted {
+                        continue;
+                    }
+                    if let Some(path) = delta.new_file().path().and_then(Path::to_str) {
+                        self.writer.delete_term(self.file_id(slug, path));
+                        if delta.new_file().mode() == git2::FileMode::Blob || delta.new_file().mode() == git2::FileMode::BlobExecutable {
+                            self.add_file(slug, path, delta.new_file().id(), repo)?;
+                        }
+                    }
+                }
+            }
+            _ => {
+                self.writer.delete_query(Box::new(BooleanQuery::new(vec![
+                    (Occur::Must, term_query(self.fields.repo, slug)),
+                    (Occur::Must, term_query(self.fields.kind, "file")),
+                ])))?;
+                if let Some(tree) = &tree {
+                    let mut files = Vec::new();
+                    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+                        if entry.kind() == Some(ObjectType::Blob) && entry.filemode() != i32::from(git2::FileMode::Link) {
+                            files.push((format!("{}{}", root, entry.name().unwrap_or_default()), entry.id()));
+                        }
+                        TreeWalkResult::Ok
+                    })?;
+                    for (path, id) in files {
+                        self.add_file(slug, &path, id, repo)?;
+                    }
+                }
+            }
+        }
+        state.tree = tree.map(|tree| tree.id().to_string());
+        Ok(())
+    }
+
+    fn add_file(&self, slug: &str, path: &str, id: Oid, repo: &Repository) -> Result<()> {
+        let blob = repo.find_blob(id)?;
+        if blob.is_binary() || blob.size() > MAX_BLOB_SIZE {
+            return Ok(());
+        }
+        let mut document = TantivyDocument::default();
+        document.add_text(self.fields.id, format!("file:{}:{}", slug, path));
+        document.add_text(self.fields.kind, "file");
+        document.add_text(self.fields.repo, slug);
+        document.add_text(self.fields.path, path);
+        if let Some(language) = self.language(path) {
+            document.add_text(self.fields.language, language);
+        }
+        document.add_text(self.fields.content, String::from_utf8_lossy(blob.content()));
+        self.writer.add_document(document)?;
+        Ok(())
+    }
+
+    fn language(&self, path: &str) -> Option<&str> {
+        let name = path.rsplit('/').next()?;
+        // Files like `Makefile` are known by their whole name.
+        let extension = name.rsplit_once('.').map(|(_, extension)| extension).unwrap_or(name);
+        self.syntaxes.find_syntax_by_extension(extension).map(|syntax| syntax.name.as_str())
+    }
+
+    fn update_commits(&mut self, slug: &str, repo: &Repository, state: &mut RepoState) -> Result<()> {
+        let mut tips = BTreeSet::new();
+        for glob in ["refs/heads/*", "refs/tags/*"] {
+            for reference in repo.references_glob(glob)? {
+                if let Ok(commit) = reference?.peel_to_commit() {
+                    tips.insert(commit.id());
+                }
+            }
+        }
+
+        // Only new history is indexed, unless some was lost to a force-push
+        // or a deleted branch. Then everything is redone, so commits the
+        // mirror no longer has stop turning up.
+        let old_tips = state.tips.iter().filter_map(|tip| Oid::from_str(tip).ok()).collect::<Vec<_>>();
+        // Most tips, tags especially, don't move between updates, so only the
+        // ones that did are checked against the ancestry of the new tips.
+        let rewound = old_tips.iter()
+            .filter(|old| !tips.contains(old))
+            .any(|old| !tips.iter().any(|tip| repo.graph_descendant_of(*tip, *old).unwrap_or(false)));
+        let mut walk = repo.revwalk()?;
+        for tip in &tips {
+            walk.push(*tip)?;
+        }
+        if rewound {
+            self.writer.delete_query(Box::new(BooleanQuery::new(vec![
+                (Occur::Must, term_query(self.fields.repo, slug)),
+                (Occur::Must, term_query(self.fields.kind, "commit")),
+            ])))?;
+        } else {
+            for tip in old_tips {
+                walk.hide(tip)?;
+            }
+        }
+
+        for id in walk {
+            let commit = repo.find_commit(id?)?;
+            let id = format!("commit:{}:{}", slug, commit.id());
+            self.writer.delete_term(Term::from_field_text(self.fields.id, &id));
+
+            let author = commit.author();
+            let mut document = TantivyDocument::default();
+            document.add_text(self.fields.id, id);
+            document.add_text(self.fields.kind, "commit");
+            document.add_text(self.fields.repo, slug);
+            document.add_text(self.fields.commit, commit.id().to_string());
+            document.add_text(self.fields.content, String::from_utf8_lossy(commit.message_bytes()));
+            document.add_text(self.fields.author, format!("{} <{}>", author.name().unwrap_or_default(), author.email().unwrap_or_default()));
+            document.add_text(self.fields.date, format_time(author.when()));
+            self.writer.add_document(document)?;
+        }
+
+        state.tips = tips.iter().map(Oid::to_string).collect();
+        Ok(())
+    }
+
+    fn file_id(&self, slug: &str, path: &str) -> Term {
+        Term::from_field_text(self.fields.id, &format!("file:{}:{}", slug, path))
+    }
+
+}
+
+fn term_query(field: Field, value: &str) -> Box<dyn Query> {
+    Box::new(TermQuery::new(Term::from_field_text(field, value), IndexRecordOption::Basic))
+}
+
+/// Opens the index for reading, or returns `None` if it hasn't been built yet.
+/// Readers are kept around and pick up new commits to the index by themselves.
+fn reader(config: &Config) -> Result<Option<(Index, IndexReader)>> {
+    let dir = index_dir(config);
+    if !dir.join("meta.json").exists() {
+        return Ok(None);
+    }
+    let mut readers = READERS.get_or_init(Default::default).lock().unwrap();
+    if let Some(reader) = readers.get(&dir) {
+        return Ok(Some(reader.clone()));
+    }
+    let index = Index::open_in_dir(&dir)?;
+    let reader = index.reader()?;
+    readers.insert(dir, (index.clone(), reader.clone()));
+    Ok(Some((index, reader)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    Code,
+    Commits,
+}
+
+/// Restricts a search. `language` and `path` only apply to code and `author`
+/// only to commits.
+#[derive(Debug, Default)]
+pub(crate) struct Filters<'a> {
+    pub repo: Option<&'a str>,
+    pub language: Option<&'a str>,
+    /// A glob that the whole path has to match.
+    pub path: Option<&'a str>,
+    pub author: Option<&'a str>,
+}
+
+pub(crate) struct Hits {
+    pub total: usize,
+    pub hits: Vec<liquid::Object>,
+}
+
+/// Runs a search over every repo that isn't hidden, or returns `None` if
+/// nothing has been indexed yet.
+pub(crate) fn search(config: &Config, text: &str, kind: Kind, filters: &Filters, offset: usize, limit: usize) -> Result<Option<Hits>> {
+    let (index, reader) = match reader(config)? {
+        Some(reader) => reader,
+        None => return Ok(None),
+    };
+    let fields = Fields::new(&index.schema())?;
+
+    let mut clauses = vec![(Occur::Must, term_query(fields.kind, match kind {
+        Kind::Code => "file",
+        Kind::Commits => "commit",
+    }))];
+    // Like grep, every word has to match.
+    let text_query = if text.trim().is_empty() {
+        None
+    } else {
+        let mut parser = QueryParser::for_index(&index, vec![fields.content]);
+        parser.set_conjunction_by_default();
+        let (query, _) = parser.parse_query_lenient(text);
+        clauses.push((Occur::Must, query.box_clone()));
+        Some(query)
+    };
+    if let Some(repo) = filters.repo {
+        clauses.push((Occur::Must, term_query(fields.repo, repo)));
+    }
+    // Only repos that are configured and visible, so the total matches the hits.
+    let visible = config.repos.iter()
+        .filter(|(_, repo)| !repo.hidden)
+        .map(|(slug, _)| (Occur::Should, term_query(fields.repo, slug)))
+        .collect();
+    clauses.push((Occur::Must, Box::new(BooleanQuery::new(visible))));
+    match kind {
+        Kind::Code => {
+            if let Some(language) = filters.language {
+                clauses.push((Occur::Must, term_query(fields.language, language)));
+            }
+            if let Some(path) = filters.path {
+                clauses.push((Occur::Must, Box::new(RegexQuery::from_pattern(&glob_to_regex(path), fields.path)?)));
+            }
+        }
+        Kind::Commits => {
+            if let Some(author) = filters.author {
+                let mut parser = QueryParser::for_index(&index, vec![fields.author]);
+                parser.set_conjunction_by_default();
+                clauses.push((Occur::Must, parser.parse_query_lenient(author).0));
+            }
+        }
+    }
+    if clauses.len() == 1 {
+        clauses.push((Occur::Must, Box::new(AllQuery)));
+    }
+    let query = BooleanQuery::new(clauses);
+
+    let searcher = reader.searcher();
+    let (top, total) = searcher.search(&query, &(TopDocs::with_limit(limit).and_offset(offset), Count))?;
+    let mut snippets = match &text_query {
+        Some(text_query) => Some(SnippetGenerator::create(&searcher, text_query.as_ref(), fields.content)?),
+        None => None,
+    };
+    if let Some(snippets) = &mut snippets {
+        snippets.set_max_num_chars(300);
+    }
+
+    let mut hits = Vec::new();
+    for (_, address) in top {
+        let document = searcher.doc::<TantivyDocument>(address)?;
+        let get = |field| document.get_first(field).and_then(|value| value.as_str()).unwrap_or_default().to_owned();
+        let snippet = snippets.as_ref()
+            .map(|snippets| {
+                let mut snippet = snippets.snippet_from_doc(&document);
+                snippet.set_snippet_prefix_postfix("<mark>", "</mark>");
+                snippet
+            })
+            .filter(|snippet| !snippet.is_empty())
+            .map(|snippet| snippet.to_html());
+
+        let slug = get(fields.repo);
+        let (title, head) = match config.repos.get(&slug) {
+            Some(repo) => (repo.title.clone(), repo.head.clone()),
+            None => continue,
+        };
+        let content = get(fields.content);
+        let commit = get(fields.commit);
+        let path = get(fields.path);
+        hits.push(liquid::object!({
+            "repo": {
+                "slug": slug,
+                "name": title,
+                "head": head,
+            },
+            "path": path,
+            "url_path": encode_path(&path),
+            "language": get(fields.language),
+            "commit": commit,
+            "short_id": commit.get(..7).unwrap_or_default(),
+            "summary": content.lines().next().unwrap_or_default(),
+            "author": get(fields.author),
+            "date": get(fields.date),
+            "snippet": snippet,
+        }));
+    }
+    Ok(Some(Hits { total, hits }))
+}
+
+/// The languages of all indexed files, for the language filter.
+pub(crate) fn languages(config: &Config) -> Result<Vec<String>> {
+    let (index, reader) = match reader(config)? {
+        Some(reader) => reader,
+        None => return Ok(Vec::new()),
+    };
+    let field = Fields::new(&index.schema())?.language;
+    let mut languages = BTreeSet::new();
+    for segment in reader.searcher().segment_readers() {
+        let inverted_index = segment.inverted_index(field)?;
+        let mut terms = inverted_index.terms().stream()?;
+        while terms.advance() {
+            languages.insert(String::from_utf8_lossy(terms.key()).into_owned());
+        }
+    }
+    Ok(languages.into_iter().collect())
+}
+
+/// Turns a path glob into a regex for the whole path. `*` and `?` stay within
+/// a directory and `**` spans any number of them.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_to_regex;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        regex::Regex::new(&format!("^{}$", glob_to_regex(glob))).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn stars_stay_within_a_directory() {
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/routes/repo.rs"));
+        assert!(matches("*.md", "README.md"));
+        assert!(!matches("*.md", "docs/guide.md"));
+    }
+
+    #[test]
+    fn double_stars_span_directories() {
+        assert!(matches("src/**/*.rs", "src/main.rs"));
+        assert!(matches("src/**/*.rs", "src/routes/repo.rs"));
+        assert!(matches("**/*.md", "README.md"));
+        assert!(matches("docs/**", "docs/a/b.txt"));
+        assert!(!matches("src/**/*.rs", "tests/main.rs"));
+    }
+
+    #[test]
+    fn question_marks_match_one_character() {
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "a/c"));
+        assert!(!matches("a?c", "abbc"));
+    }
+
+    #[test]
+    fn regex_characters_are_literal() {
+        assert!(matches("a+b(1).txt", "a+b(1).txt"));
+        assert!(!matches("a.txt", "abtxt"));
+    }
+}
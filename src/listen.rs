@@ -0,0 +1,180 @@
+use std::{fs::{self, File}, io::{self, BufReader}, net::SocketAddr, os::unix::fs::{FileTypeExt, PermissionsExt}, path::Path, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use axum::Router;
+use futures_util::future::select_all;
+use hyper::server::conn::Http;
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpListener, UnixListener}};
+use tokio_rustls::{TlsAcceptor, rustls::{Certificate, PrivateKey, ServerConfig}};
+
+use crate::{config::{ListenerConfig, TlsConfig}, errors::{Result, GititError}};
+
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait before accepting again after running out of file
+/// descriptors or a similar error, the same delay hyper uses for TCP.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+/// Clients that don't finish the handshake in time are dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves `app` on every configured listener, returning as soon as any of them
+/// fails.
+pub(crate) async fn serve(listeners: Vec<ListenerConfig>, app: Router) -> Result<()> {
+    if listeners.is_empty() {
+        return Err(GititError::InvalidConfig("no listeners configured".to_owned()));
+    }
+
+    let tasks = listeners.into_iter()
+        .map(|listener| tokio::spawn(run_listener(listener, app.clone())))
+        .collect::<Vec<_>>();
+    let (result, _, _) = select_all(tasks).await;
+    result?
+}
+
+async fn run_listener(listener: ListenerConfig, app: Router) -> Result<()> {
+    match (&listener.address, &listener.unix, &listener.tls) {
+        (Some(address), None, None) => {
+            let address = parse_address(address)?;
+            tracing::info!("listening on http://{}", address);
+            axum::Server::try_bind(&address)?
+                .serve(app.into_make_service())
+                .await?;
+            Ok(())
+        }
+        (Some(address), None, Some(tls)) => serve_tls(parse_address(address)?, tls.clone(), app).await,
+        (None, Some(path), None) => serve_unix(path, listener.mode, app).await,
+        _ => Err(GititError::InvalidConfig(format!("listener must have either an address or a unix socket path, and tls only applies to addresses: {:?}", listener))),
+    }
+}
+
+fn parse_address(address: &str) -> Result<SocketAddr> {
+    address.parse()
+        .map_err(|e| GititError::InvalidConfig(format!("{:?} is not a valid socket address: {}", address, e)))
+}
+
+fn serve_connection<S>(stream: S, app: Router)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = Http::new().serve_connection(stream, app).with_upgrades().await {
+            tracing::debug!("connection error: {}", e);
+        }
+    });
+}
+
+/// Logs a failed accept and waits a moment if it isn't just a client that
+/// went away, so the listener keeps running.
+async fn accept_failed(e: io::Error) {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => {
+            tracing::debug!("accept error: {}", e);
+        }
+        _ => {
+            tracing::error!("accept error: {}", e);
+            tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+        }
+    }
+}
+
+async fn serve_unix(path: &Path, mode: Option<u32>, app: Router) -> Result<()> {
+    // Clean up a socket left behind by a previous run, but never anything else.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    tracing::info!("listening on unix:{}", path.display());
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => serve_connection(stream, app.clone()),
+            Err(e) => accept_failed(e).await,
+        }
+    }
+}
+
+async fn serve_tls(address: SocketAddr, tls: TlsConfig, app: Router) -> Result<()> {
+    let current = Arc::new(RwLock::new(load_tls(&tls)?));
+    tokio::spawn(watch_tls(tls, current.clone()));
+
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("listening on https://{}", address);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_failed(e).await;
+                continue;
+            }
+        };
+        let acceptor = TlsAcceptor::from(current.read().unwrap().clone());
+        let app = app.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => serve_connection(stream, app),
+                Ok(Err(e)) => tracing::debug!("tls handshake with {} failed: {}", peer, e),
+                Err(_) => tracing::debug!("tls handshake with {} timed out", peer),
+            }
+        });
+    }
+}
+
+fn load_tls(tls: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(GititError::InvalidConfig(format!("no certificates found in {:?}", tls.cert)));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(&tls.key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| GititError::InvalidConfig(format!("no private key found in {:?}", tls.key)))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn modified(tls: &TlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(&tls.cert), modified(&tls.key))
+}
+
+/// Reloads the certificate and key when either file changes, so renewed
+/// certificates are picked up without a restart.
+async fn watch_tls(tls: TlsConfig, current: Arc<RwLock<Arc<ServerConfig>>>) {
+    let mut interval = tokio::time::interval(CERT_POLL_INTERVAL);
+    let mut last_modified = modified(&tls);
+    loop {
+        interval.tick().await;
+        let now_modified = modified(&tls);
+        if now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
+
+        match load_tls(&tls) {
+            Ok(config) => {
+                *current.write().unwrap() = config;
+                tracing::info!("reloaded tls certificate {:?}", tls.cert);
+            }
+            Err(e) => tracing::error!("failed to reload tls certificate {:?}, keeping the old one: {}", tls.cert, e),
+        }
+    }
+}
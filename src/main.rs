@@ -1,19 +1,42 @@
+use std::path::PathBuf;
+
 use axum::Extension;
 use clap::Parser;
-use config::Config;
+use config::{Config, SharedConfig};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::prelude::*;
 
+mod check;
+mod combined_diff;
 mod config;
 mod errors;
+mod index;
+mod listen;
+mod readme;
+mod reload;
 mod routes;
+mod search;
+mod signature;
 mod update;
 mod utils;
+mod verify;
 
 #[derive(clap::Parser)]
-enum Cli {
+struct Cli {
+    /// Path to the configuration file
+    #[clap(long, global = true, env = "GITIT_CONFIG", default_value = "gitit.toml")]
+    config: PathBuf,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
     Web,
     UpdateRepos,
+    Verify,
+    /// Validate the config file and the mirrors it refers to
+    CheckConfig,
 }
 
 #[tokio::main]
@@ -26,21 +49,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
 
     let cli = Cli::parse();
-    let config = config::load()?;
-    match cli {
-        Cli::Web => run_server(config).await,
-        Cli::UpdateRepos => update::update_repos(config).map_err(|e| e.into()),
+    match cli.command {
+        Command::Web => run_server(cli.config.clone(), config::load(&cli.config)?).await,
+        Command::UpdateRepos => update::update_repos(config::load(&cli.config)?).map_err(|e| e.into()),
+        Command::Verify => verify::verify_repos(config::load(&cli.config)?).map_err(|e| e.into()),
+        Command::CheckConfig => {
+            if check::check_config(&cli.config).is_err() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
     }
 }
 
-async fn run_server(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let app = routes::build_router()
+async fn run_server(config_path: PathBuf, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    // Hold the initial config to the same checks a reload has to pass.
+    let errors = check::validate(&config);
+    if !errors.is_empty() {
+        for error in &errors {
+            tracing::error!("{}", error);
+        }
+        return Err(errors::GititError::InvalidConfig(format!("{} error(s) in {:?}", errors.len(), config_path)).into());
+    }
+
+    let listeners = config.server.listeners();
+    let base_path = config.server.base_path.clone();
+    let shared = SharedConfig::new(config);
+    tokio::spawn(reload::watch(config_path, shared.clone()));
+
+    let app = routes::build_router(&base_path)
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(config.clone()));
+        .layer(Extension(shared));
+
+    listen::serve(listeners, app).await?;
 
-    axum::Server::bind(&config.server.address.parse().unwrap())
-        .serve(app.into_make_service())
-        .await?;
-    
     Ok(())
 }
@@ -0,0 +1,124 @@
+use std::borrow::Cow;
+
+use git2::{Blob, Repository, Tree};
+use percent_encoding::utf8_percent_encode;
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+use crate::utils::SEGMENT;
+
+/// README extensions in order of preference. Anything but Markdown is shown
+/// as plain text.
+const EXTENSIONS: &[&str] = &["md", "markdown", "rst", "txt", ""];
+
+/// Finds the README in `tree`, preferring Markdown if there are several.
+pub(crate) fn find<'repo>(repo: &'repo Repository, tree: &Tree) -> Option<(String, Blob<'repo>)> {
+    let (_, name, id) = tree.iter()
+        .filter(|entry| entry.kind() == Some(git2::ObjectType::Blob))
+        .filter_map(|entry| {
+            let name = entry.name()?.to_owned();
+            let (stem, extension) = name.split_once('.').unwrap_or((&name, ""));
+            if !stem.eq_ignore_ascii_case("readme") {
+                return None;
+            }
+            let rank = EXTENSIONS.iter().position(|e| e.eq_ignore_ascii_case(extension))?;
+            Some((rank, name, entry.id()))
+        })
+        .min_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)))?;
+    let blob = repo.find_blob(id).ok()?;
+    if blob.is_binary() {
+        return None;
+    }
+    Some((name, blob))
+}
+
+/// Where a README lives, used to turn its relative links into gitit URLs.
+pub(crate) struct Links<'a> {
+    pub base: &'a str,
+    pub slug: &'a str,
+    pub rev: &'a str,
+    /// The directory containing the README, with leading and trailing slashes.
+    pub dir: &'a str,
+}
+
+impl Links<'_> {
+    fn url(&self, kind: &str, dest: &str) -> Option<String> {
+        if dest.starts_with('#') || dest.starts_with("//") || url::Url::parse(dest).is_ok() {
+            return None;
+        }
+        let split = dest.find(['?', '#']).unwrap_or(dest.len());
+        let (path, suffix) = dest.split_at(split);
+        let joined = if path.starts_with('/') {
+            path.to_owned()
+        } else {
+            format!("{}{}", self.dir, path)
+        };
+
+        let mut segments = Vec::new();
+        for segment in joined.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => { segments.pop(); }
+                segment => segments.push(segment),
+            }
+        }
+        let trailing = if joined.ends_with('/') && !segments.is_empty() { "/" } else { "" };
+        Some(format!(
+            "{}/{}/{}/{}/{}{}{}",
+            self.base,
+            utf8_percent_encode(self.slug, SEGMENT),
+            kind,
+            utf8_percent_encode(self.rev, SEGMENT),
+            segments.join("/"),
+            trailing,
+            suffix,
+        ))
+    }
+}
+
+/// Renders a README to HTML. Markdown is rendered and sanitized, with relative
+/// links pointing at the blob view and images at the raw endpoint; other
+/// formats are shown as preformatted text.
+pub(crate) fn render(name: &str, content: &[u8], links: &Links) -> String {
+    let text = String::from_utf8_lossy(content);
+    let is_markdown = name.rsplit_once('.')
+        .map(|(_, extension)| extension.eq_ignore_ascii_case("md") || extension.eq_ignore_ascii_case("markdown"))
+        .unwrap_or(false);
+    if !is_markdown {
+        return format!("<pre>{}</pre>", html_escape::encode_text(&text));
+    }
+
+    let parser = Parser::new_ext(&text, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS)
+        .map(|event| match event {
+            Event::Start(Tag::Link(kind, dest, title)) => {
+                let dest = links.url("blob", &dest).map(Into::into).unwrap_or(dest);
+                Event::Start(Tag::Link(kind, dest, title))
+            }
+            Event::Start(Tag::Image(kind, dest, title)) => {
+                let dest = links.url("raw", &dest).map(Into::into).unwrap_or(dest);
+                Event::Start(Tag::Image(kind, dest, title))
+            }
+            event => event,
+        });
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    // Relative URLs left over at this point come from inline HTML, which is
+    // mostly `<img>` tags, so point them at the raw file.
+    let raw_prefix = links.url("raw", "").unwrap_or_default();
+    ammonia::Builder::default()
+        .url_relative(ammonia::UrlRelative::Custom(Box::new(RawRelative(raw_prefix))))
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+struct RawRelative(String);
+
+impl ammonia::UrlRelativeEvaluate for RawRelative {
+    fn evaluate<'a>(&self, url: &'a str) -> Option<Cow<'a, str>> {
+        if url.starts_with('/') || url.starts_with('#') {
+            Some(Cow::Borrowed(url))
+        } else {
+            Some(Cow::Owned(format!("{}{}", self.0, url)))
+        }
+    }
+}
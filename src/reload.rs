@@ -0,0 +1,74 @@
+use std::{path::{Path, PathBuf}, time::{Duration, SystemTime}, fs};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{check, config::{self, SharedConfig}};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Modification times of every file that makes up the config, used to notice
+/// when any of them change or when an include starts matching a new file.
+fn snapshot(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let sources = config::sources(path).unwrap_or_else(|_| vec![path.to_owned()]);
+    sources.into_iter()
+        .map(|source| {
+            let modified = fs::metadata(&source).and_then(|m| m.modified()).ok();
+            (source, modified)
+        })
+        .collect()
+}
+
+/// Reloads the config whenever one of its files changes on disk or the process
+/// receives SIGHUP. A config that fails to load is logged and ignored, so the
+/// server keeps running with the last good one.
+pub(crate) async fn watch(path: PathBuf, shared: SharedConfig) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("failed to listen for SIGHUP, config will not be reloaded: {}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut last_snapshot = snapshot(&path);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                tracing::info!("received SIGHUP, reloading config");
+            }
+            _ = interval.tick() => {
+                if snapshot(&path) == last_snapshot {
+                    continue;
+                }
+                tracing::info!("config files changed, reloading config");
+            }
+        }
+        last_snapshot = snapshot(&path);
+
+        let config = match config::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("failed to reload config, keeping the old one: {}", e);
+                continue;
+            }
+        };
+        let errors = check::validate(&config);
+        if !errors.is_empty() {
+            for error in errors {
+                tracing::error!("{}", error);
+            }
+            tracing::error!("reloaded config is invalid, keeping the old one");
+            continue;
+        }
+
+        let mut config = config;
+        let current = shared.load();
+        if config.server.listeners() != current.server.listeners() || config.server.base_path != current.server.base_path {
+            tracing::warn!("changes to the [server] section require a restart and have been ignored");
+            config.server = current.server.clone();
+        }
+        shared.store(config);
+        tracing::info!("config reloaded");
+    }
+}
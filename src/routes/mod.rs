@@ -3,12 +3,28 @@ use axum::{Router, routing::get};
 mod assets;
 mod repo;
 
-pub fn build_router() -> Router {
-    Router::new()
+pub fn build_router(base_path: &str) -> Router {
+    let router = Router::new()
         .route("/", get(repo::list))
+        .route("/search", get(repo::search_all))
         .route("/:repo/", get(repo::index))
         .route("/:repo/commit/:commit_id/", get(repo::commit))
         .route("/:repo/commit/:commit_id/contents/*tree_path", get(repo::commit_tree))
         .route("/:repo/commit/:commit_id/diff", get(repo::commit_raw))
-        .route("/assets/*path", get(assets::get))
+        .route("/:repo/tags", get(repo::tags))
+        .route("/:repo/search", get(repo::search))
+        .route("/:repo/compare/*spec", get(repo::compare))
+        .route("/:repo/log/*ref_path", get(repo::log))
+        .route("/:repo/archive/*ref_path", get(repo::archive))
+        .route("/:repo/raw/*ref_path", get(repo::raw))
+        .route("/:repo/blame/*ref_path", get(repo::blame))
+        .route("/:repo/tree/*ref_path", get(repo::ref_tree))
+        .route("/:repo/blob/*ref_path", get(repo::ref_blob))
+        .route("/assets/*path", get(assets::get));
+
+    if base_path.is_empty() {
+        router
+    } else {
+        Router::new().nest(base_path, router)
+    }
 }
@@ -1,62 +1,219 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, fs, path::PathBuf, time::{Duration, Instant}};
 
-use axum::{extract::{Path, OriginalUri}, response::{Html, IntoResponse}, http::header, Extension};
-use git2::{Repository, Sort, Tree, Blob, BranchType};
+use axum::{body::{Bytes, StreamBody}, extract::{Path, Query}, response::{Html, IntoResponse}, http::{header, StatusCode, Uri}, headers::{ETag, HeaderMapExt, IfNoneMatch}, Extension, TypedHeader};
+use flate2::{write::GzEncoder, Compression};
+use git2::{Repository, Sort, Tree, Blob, BranchType, Commit, Oid, Delta, DiffFindOptions, BlameOptions};
+use serde::Deserialize;
 
-use crate::{errors::{Result, GititError}, utils::{templates, ObjectId, HtmlOrRaw, safe_mime}, config::{Config, RepoConfig}};
+use crate::{errors::{Result, GititError}, utils::{templates::{self, DiffView}, ObjectId, HtmlOrRaw, safe_mime, compare_versions, encode_path}, config::{Config, RepoConfig, SharedConfig}, index, readme, search, signature, verify};
 
 fn repo_from_name<'config>(repo_name: &str, config: &'config Config) -> Result<(&'config RepoConfig, Repository)> {
     let repo_config = config.repos.get(repo_name).ok_or(GititError::NotFound)?;
-    let mut path = PathBuf::new();
-    path.push("repos");
-    path.push(format!("{}.git", repo_name));
-    let repo = Repository::open_bare(path)
+    let repo = Repository::open_bare(config.repo_path(repo_name, repo_config))
         .map_err(|e| {
             match e.code() {
                 git2::ErrorCode::NotFound => GititError::NotFound,
                 _ => e.into(),
             }
         })?;
+    verify::check_status(&repo)?;
     Ok((repo_config, repo))
 }
 
+/// A template parser that knows the partials shared between pages.
+fn parser() -> Result<liquid::Parser> {
+    let mut partials = liquid::partials::EagerCompiler::<liquid::partials::InMemorySource>::empty();
+    partials.add("signature", include_str!("templates/repo/signature.html.liquid"));
+    partials.add("diff_files", include_str!("templates/repo/diff_files.html.liquid"));
+    partials.add("list_item", include_str!("templates/repo/list_item.html.liquid"));
+    Ok(liquid::ParserBuilder::with_stdlib().partials(partials).build()?)
+}
+
+/// Placeholder written by `git init` that shouldn't be shown to anyone.
+const DEFAULT_DESCRIPTION: &str = "Unnamed repository; edit this file 'description' to name the repository.";
+
+fn repo_description(slug: &str, repo_config: &RepoConfig, config: &Config) -> Option<String> {
+    if let Some(description) = &repo_config.description {
+        return Some(description.clone());
+    }
+    let description = fs::read_to_string(config.repo_path(slug, repo_config).join("description")).ok()?;
+    let description = description.trim();
+    if description.is_empty() || description == DEFAULT_DESCRIPTION {
+        None
+    } else {
+        Some(description.to_owned())
+    }
+}
+
+fn repo_to_object(slug: &str, repo_config: &RepoConfig, config: &Config) -> liquid::Object {
+    let mut clone_urls = vec![repo_config.url.clone()];
+    clone_urls.extend(repo_config.clone_urls.iter().cloned());
+    liquid::object!({
+        "slug": slug,
+        "title": repo_config.title,
+        "upstream_url": repo_config.url,
+        "description": repo_description(slug, repo_config, config),
+        "homepage": repo_config.homepage,
+        "owner": repo_config.owner,
+        "maintainers": repo_config.maintainers,
+        "topics": repo_config.topics,
+        "clone_urls": clone_urls,
+    })
+}
+
 #[tracing::instrument]
-pub(crate) async fn list(Extension(config): Extension<Config>) -> Result<Html<String>> {
+pub(crate) async fn list(Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
+    let template = parser()?
+        .parse(include_str!("templates/repo/list.html.liquid"))?;
+
+    let mut visible = config.repos.iter()
+        .filter(|(_, repo)| !repo.hidden)
+        .collect::<Vec<_>>();
+    visible.sort_by_key(|(slug, _)| *slug);
+
+    let mut groups = BTreeMap::<&str, Vec<liquid::Object>>::new();
+    let mut ungrouped = Vec::new();
+    for (slug, repo) in visible {
+        let object = repo_to_object(slug, repo, &config);
+        match repo.group(slug) {
+            Some(group) => groups.entry(group).or_default().push(object),
+            None => ungrouped.push(object),
+        }
+    }
+
+    let groups = groups.into_iter()
+        .map(|(name, repos)| liquid::object!({
+            "name": name,
+            "repos": repos,
+        }))
+        .collect::<Vec<_>>();
+
+    Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
+        "groups": groups,
+        "ungrouped": ungrouped,
+    }))?))
+}
+
+/// Results shown per page of a search across all repos.
+const SEARCH_PAGE_SIZE: usize = 20;
+/// Deeper pages make the index collect every result before them, so stop
+/// offering more well before that gets expensive.
+const SEARCH_MAX_PAGE: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SearchAllQuery {
+    #[serde(default)]
+    q: String,
+    /// `code` (the default) or `commits`.
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    repo: Option<String>,
+    language: Option<String>,
+    path: Option<String>,
+    author: Option<String>,
+    #[serde(default = "first_page")]
+    page: usize,
+}
+
+fn first_page() -> usize {
+    1
+}
+
+/// Empty form fields mean no filter.
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|value| !value.is_empty())
+}
+
+#[tracing::instrument]
+pub(crate) async fn search_all(Query(query): Query<SearchAllQuery>, Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
     let template = liquid::ParserBuilder::with_stdlib()
         .build()?
-        .parse(include_str!("templates/repo/list.html.liquid"))?;
+        .parse(include_str!("templates/repo/search_all.html.liquid"))?;
+
+    let kind = match query.kind.as_deref() {
+        Some("commits") => index::Kind::Commits,
+        _ => index::Kind::Code,
+    };
+    let filters = index::Filters {
+        repo: non_empty(&query.repo),
+        language: non_empty(&query.language),
+        path: non_empty(&query.path),
+        author: non_empty(&query.author),
+    };
+    let page = query.page.max(1);
+    if page > SEARCH_MAX_PAGE {
+        return Err(GititError::NotFound);
+    }
 
-    let mut repos = Vec::with_capacity(config.repos.len());
-    for (slug, repo) in config.repos {
-        repos.push(liquid::object!({
+    let mut error = None;
+    let mut results = None;
+    if let Some(Err(e)) = filters.path.map(glob::Pattern::new) {
+        error = Some(format!("Invalid path pattern: {}", e));
+    } else if !query.q.trim().is_empty() || filters.repo.is_some() || filters.author.is_some() {
+        match index::search(&config, &query.q, kind, &filters, (page - 1) * SEARCH_PAGE_SIZE, SEARCH_PAGE_SIZE)? {
+            Some(hits) => results = Some(hits),
+            None => error = Some("The search index hasn't been built yet, it is created by `gitit update-repos`.".to_owned()),
+        }
+    }
+
+    let mut visible = config.repos.iter()
+        .filter(|(_, repo)| !repo.hidden)
+        .collect::<Vec<_>>();
+    visible.sort_by_key(|(slug, _)| *slug);
+    let repos = visible.into_iter()
+        .map(|(slug, repo)| liquid::object!({
             "slug": slug,
-            "title": repo.title,
-            "upstream_url": repo.url,
-        }));
+            "name": repo.title,
+        }))
+        .collect::<Vec<_>>();
+
+    // Everything but the page, for the pagination links.
+    let mut href = url::form_urlencoded::Serializer::new(String::new());
+    href.append_pair("q", &query.q);
+    href.append_pair("type", if kind == index::Kind::Commits { "commits" } else { "code" });
+    for (name, value) in [("repo", filters.repo), ("language", filters.language), ("path", filters.path), ("author", filters.author)] {
+        if let Some(value) = value {
+            href.append_pair(name, value);
+        }
     }
+    let href = format!("?{}", href.finish());
 
+    let total = results.as_ref().map(|r| r.total).unwrap_or_default();
     Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
+        "query": {
+            "q": query.q,
+            "type": if kind == index::Kind::Commits { "commits" } else { "code" },
+            "repo": filters.repo,
+            "language": filters.language,
+            "path": filters.path,
+            "author": filters.author,
+        },
         "repos": repos,
+        "languages": index::languages(&config)?,
+        "error": error,
+        "searched": results.is_some(),
+        "total": total,
+        "hits": results.map(|r| r.hits).unwrap_or_default(),
+        "previous": (page > 1).then(|| format!("{}&page={}", href, page - 1)),
+        "next": (page < SEARCH_MAX_PAGE && page * SEARCH_PAGE_SIZE < total).then(|| format!("{}&page={}", href, page + 1)),
     }))?))
 }
 
 #[tracing::instrument]
-pub(crate) async fn index(Path(repo_name): Path<String>, Extension(config): Extension<Config>) -> Result<Html<String>> {
+pub(crate) async fn index(Path(repo_name): Path<String>, Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
     let template = liquid::ParserBuilder::with_stdlib()
         .build()?
         .parse(include_str!("templates/repo/index.html.liquid"))?;
 
     let (repo_config, repo) = repo_from_name(&repo_name, &config)?;
-    let mut revwalk = repo.revwalk()?;
-    revwalk.push_head()?;
-    revwalk.set_sorting(Sort::TIME)?;
-
-    let mut commits = Vec::<liquid::Object>::with_capacity(100);
-    for commit in revwalk.take(500) {
-        let commit_id = commit?;
-        let commit = repo.find_commit(commit_id)?;
-        commits.push(templates::commit_to_object(&repo, &commit)?);
-    }
+    let head = repo.head()?.peel_to_commit()?;
+    let (commits, next) = log_page(&repo, &head, None, None, false, &config)?;
+    let commits = commits.into_iter().map(|(_, commit)| commit).collect::<Vec<_>>();
 
     let mut branches = Vec::with_capacity(10);
     for branch in repo.branches(Some(BranchType::Local))? {
@@ -68,75 +225,892 @@ pub(crate) async fn index(Path(repo_name): Path<String>, Extension(config): Exte
         }));
     }
 
+    let head_name = repo.head()?.shorthand().unwrap_or("HEAD").to_owned();
+    let links = readme::Links { base: &config.server.base_path, slug: &repo_name, rev: &head_name, dir: "/" };
+    let readme = readme_to_object(&repo, &head.tree()?, &links);
+
     let repo = liquid::object!({
         "name": repo_config.title,
+        "info": repo_to_object(&repo_name, repo_config, &config),
+        "slug": repo_name,
+        "head": head_name,
+        "readme": readme,
         "recent_commits": commits,
+        "has_more_commits": next.is_some(),
         "branches": branches,
     });
 
     Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
         "repo": repo,
     }))?))
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct CommitQuery {
+    /// Which parent of a merge to diff against, counting from 1 like `^N`.
+    parent: Option<usize>,
+    #[serde(default)]
+    combined: bool,
+    /// Show old and new lines side by side.
+    #[serde(default)]
+    split: bool,
+    /// Start with every file's diff collapsed.
+    #[serde(default)]
+    collapsed: bool,
+}
+
+impl CommitQuery {
+    fn view(&self, commit: &Commit) -> Result<DiffView> {
+        if self.combined && commit.parent_count() > 1 {
+            return Ok(DiffView::Combined);
+        }
+        match self.parent {
+            None => Ok(DiffView::Parent(0)),
+            Some(n) if n >= 1 && n <= commit.parent_count().max(1) => Ok(DiffView::Parent(n - 1)),
+            Some(_) => Err(GititError::NotFound),
+        }
+    }
+
+    /// A link to this page with `change` applied to the current options.
+    fn href(&self, change: impl FnOnce(&mut CommitQuery)) -> String {
+        let mut query = self.clone();
+        change(&mut query);
+        let mut params = Vec::new();
+        if let Some(parent) = query.parent {
+            params.push(format!("parent={}", parent));
+        }
+        for (name, set) in [("combined", query.combined), ("split", query.split), ("collapsed", query.collapsed)] {
+            if set {
+                params.push(format!("{}=true", name));
+            }
+        }
+        format!("?{}", params.join("&"))
+    }
+}
+
 #[tracing::instrument]
-pub(crate) async fn commit(Path((repo_name, ObjectId(commit))): Path<(String, ObjectId)>, Extension(config): Extension<Config>) -> Result<impl IntoResponse> {
-    let template = liquid::ParserBuilder::with_stdlib()
-        .build()?
+pub(crate) async fn commit(Path((repo_name, ObjectId(commit))): Path<(String, ObjectId)>, Query(query): Query<CommitQuery>, Extension(config): Extension<SharedConfig>) -> Result<impl IntoResponse> {
+    let config = config.load();
+    let template = parser()?
         .parse(include_str!("templates/repo/commit.html.liquid"))?;
 
     let (repo_config, repo) = repo_from_name(&repo_name, &config)?;
+    let signed = signature::commit_signature(&repo, commit).into_iter().collect();
+    let verification = signature::verify_all(signed, &config.signatures).await?.remove(&commit);
     let commit = repo.find_commit(commit)?;
+    let view = query.view(&commit)?;
     let repo_data = liquid::object!({
         "name": repo_config.title,
     });
+    let parents = commit.parent_ids().enumerate()
+        .map(|(i, id)| liquid::object!({
+            "number": i + 1,
+            "short_hash": id.to_string()[..7].to_owned(),
+            "selected": matches!(view, DiffView::Parent(p) if p == i),
+            "href": query.href(|q| { q.parent = Some(i + 1); q.combined = false; }),
+        }))
+        .collect::<Vec<_>>();
+
+    // The combined view of a merge stays a single patch, everything else is
+    // broken up per file.
+    let (files, diff) = match view {
+        DiffView::Parent(parent) => (templates::diff_files(&repo, &templates::makediff(&repo, &commit, parent, &config.diff)?)?, String::new()),
+        DiffView::Combined => (Vec::new(), templates::full_diff(&repo, &commit, view, &config.diff, false)?),
+    };
     Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
         "repo": repo_data,
-        "commit": templates::commit_to_object(&repo, &commit)?,
-        "diff": templates::full_diff(&repo, &commit, false)?,
+        "commit": with_signature(templates::commit_to_object(&repo, &commit)?, verification),
+        "parents": parents,
+        "combined": matches!(view, DiffView::Combined),
+        "split": query.split,
+        "collapsed": query.collapsed,
+        "links": {
+            "combined": query.href(|q| { q.parent = None; q.combined = true; }),
+            "unified": query.href(|q| q.split = false),
+            "split": query.href(|q| q.split = true),
+            "collapse": query.href(|q| q.collapsed = true),
+            "expand": query.href(|q| q.collapsed = false),
+        },
+        "files": files,
+        "diff": diff,
     }))?))
 }
 
 #[tracing::instrument]
-pub(crate) async fn commit_raw(Path((repo_name, ObjectId(commit))): Path<(String, ObjectId)>, Extension(config): Extension<Config>) -> Result<impl IntoResponse> {
+pub(crate) async fn commit_raw(Path((repo_name, ObjectId(commit))): Path<(String, ObjectId)>, Query(query): Query<CommitQuery>, Extension(config): Extension<SharedConfig>) -> Result<impl IntoResponse> {
+    let config = config.load();
     let (_, repo) = repo_from_name(&repo_name, &config)?;
     let commit = repo.find_commit(commit)?;
-    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], templates::full_diff(&repo, &commit, true)?))
+    let view = query.view(&commit)?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], templates::full_diff(&repo, &commit, view, &config.diff, true)?))
 }
 
 #[tracing::instrument]
-pub(crate) async fn commit_tree(Path((repo_name, ObjectId(commit), path)): Path<(String, ObjectId, String)>, OriginalUri(full_uri): OriginalUri, Extension(config): Extension<Config>) -> Result<HtmlOrRaw> {
+pub(crate) async fn commit_tree(Path((repo_name, ObjectId(commit), path)): Path<(String, ObjectId, String)>, uri: Uri, Extension(config): Extension<SharedConfig>) -> Result<HtmlOrRaw> {
+    let config = config.load();
     let (_, repo) = repo_from_name(&repo_name, &config)?;
     let commit = repo.find_commit(commit)?;
-    let tree = commit.tree()?;
+    let location = Location::new(&config.server.base_path, &repo_name, commit.id().to_string(), &commit);
+    browse(&location, &repo, &commit, path, &uri)
+}
+
+#[tracing::instrument]
+pub(crate) async fn ref_tree(Path((repo_name, rest)): Path<(String, String)>, uri: Uri, Extension(config): Extension<SharedConfig>) -> Result<HtmlOrRaw> {
+    let config = config.load();
+    let (_, repo) = repo_from_name(&repo_name, &config)?;
+    let (commit, rev, path) = resolve_ref(&repo, &rest)?;
+    let location = Location::new(&config.server.base_path, &repo_name, rev, &commit);
+    browse(&location, &repo, &commit, path, &uri)
+}
 
+#[tracing::instrument]
+pub(crate) async fn ref_blob(Path((repo_name, rest)): Path<(String, String)>, Extension(config): Extension<SharedConfig>) -> Result<HtmlOrRaw> {
+    let config = config.load();
+    let base = &config.server.base_path;
+    let (_, repo) = repo_from_name(&repo_name, &config)?;
+    let (commit, rev, path) = resolve_ref(&repo, &rest)?;
     if path.len() <= 1 {
-        return render_tree(&commit.id().to_string(), path, &tree);
+        return Err(GititError::NotFound);
+    }
+    let location = Location::new(base, &repo_name, rev, &commit);
+
+    let entry = commit.tree()?.get_path(std::path::Path::new(&path[1..])).map_err(|_| GititError::NotFound)?;
+    match entry.kind() {
+        Some(git2::ObjectType::Blob) => {
+            let object = entry.to_object(&repo)?;
+            let blob = object.as_blob().ok_or(GititError::NotFound)?;
+            render_file(&location, path, blob)
+        }
+        Some(git2::ObjectType::Tree) => {
+            // Directories live under /tree/ at the same ref and path.
+            Err(GititError::Redirect(format!("{}/{}/tree/{}{}/", base, encode_path(&repo_name), encode_path(&location.rev), encode_path(path.trim_end_matches('/')))))
+        }
+        _ => Err(GititError::NotFound),
+    }
+}
+
+/// The id of whatever is at `path` in `commit`, if anything.
+fn entry_at(commit: &Commit, path: &std::path::Path) -> Option<Oid> {
+    if path.as_os_str().is_empty() {
+        return Some(commit.tree_id());
+    }
+    commit.tree().ok()?.get_path(path).ok().map(|entry| entry.id())
+}
+
+/// Whether `commit` changed `path`. Like `git log -- <path>`, a merge only
+/// counts if it differs from every parent.
+fn touches(commit: &Commit, path: &std::path::Path) -> bool {
+    let entry = entry_at(commit, path);
+    if commit.parent_count() == 0 {
+        return entry.is_some();
+    }
+    commit.parents().all(|parent| entry_at(&parent, path) != entry)
+}
+
+/// If `commit` added `path` by renaming another file, returns the old path.
+fn renamed_from(repo: &Repository, commit: &Commit, path: &std::path::Path) -> Result<Option<PathBuf>> {
+    let parent = match commit.parents().next() {
+        Some(parent) => parent,
+        None => return Ok(None),
     };
+    if entry_at(&parent, path).is_some() {
+        return Ok(None);
+    }
 
-    let subtree = tree.get_path(&std::path::Path::new(&path[1..]))?;
+    let mut diff = repo.diff_tree_to_tree(Some(&parent.tree()?), Some(&commit.tree()?), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+    Ok(diff.deltas()
+        .find(|delta| delta.status() == Delta::Renamed && delta.new_file().path() == Some(path))
+        .and_then(|delta| delta.old_file().path().map(|p| p.to_owned())))
+}
 
-    match subtree.kind().unwrap() {
-        git2::ObjectType::Tree => {
-            if !path.ends_with("/") {
-                let path_and_query = full_uri.path_and_query().unwrap();
-                let target = if let Some(query) = path_and_query.query() {
-                    format!("{}/?{}", path_and_query.path(), query)
-                } else {
-                    format!("{}/", path_and_query.path())
+/// A commit on a page of history, with its id kept for checking signatures.
+type LogEntry = (Oid, liquid::Object);
+
+/// Walks history from `start`, skipping everything up to and including
+/// `after`, and returns one page of commits along with the cursor for the
+/// next page, if there is one. When `path` is given, only commits that touch
+/// it are included, and with `follow` the path is traced back across renames.
+fn log_page(repo: &Repository, start: &Commit, after: Option<Oid>, path: Option<&std::path::Path>, follow: bool, config: &Config) -> Result<(Vec<LogEntry>, Option<Oid>)> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(start.id())?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+
+    let mut path = path.map(|p| p.to_owned());
+    let mut seen_cursor = after.is_none();
+    let mut commits = Vec::with_capacity(config.web.log_page_size);
+    let mut last = None;
+    for commit_id in revwalk {
+        let commit_id = commit_id?;
+        // Only commits after the cursor go on this page, but earlier ones
+        // still need to be looked at when following renames.
+        let visible = seen_cursor;
+        if after == Some(commit_id) {
+            seen_cursor = true;
+        }
+        if !visible && (!follow || path.is_none()) {
+            continue;
+        }
+
+        let commit = repo.find_commit(commit_id)?;
+        if let Some(current) = &path {
+            if !touches(&commit, current) {
+                continue;
+            }
+        }
+
+        if visible {
+            if commits.len() == config.web.log_page_size {
+                return Ok((commits, last));
+            }
+            commits.push((commit_id, templates::commit_to_object(repo, &commit)?));
+            last = Some(commit_id);
+        }
+
+        if follow {
+            if let Some(current) = &path {
+                if let Some(old) = renamed_from(repo, &commit, current)? {
+                    path = Some(old);
+                }
+            }
+        }
+    }
+
+    if !seen_cursor {
+        return Err(GititError::NotFound);
+    }
+    Ok((commits, None))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LogQuery {
+    after: Option<ObjectId>,
+    #[serde(default)]
+    follow: bool,
+}
+
+#[tracing::instrument]
+pub(crate) async fn log(Path((repo_name, rest)): Path<(String, String)>, Query(query): Query<LogQuery>, Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
+    let template = parser()?
+        .parse(include_str!("templates/repo/log.html.liquid"))?;
+
+    let (repo_config, repo) = repo_from_name(&repo_name, &config)?;
+    let (commits, next, rev, path) = {
+        let (start, rev, path) = resolve_ref(&repo, &rest)?;
+        let path = path.trim_matches('/').to_owned();
+        let filter = if path.is_empty() {
+            None
+        } else {
+            if entry_at(&start, std::path::Path::new(&path)).is_none() {
+                return Err(GititError::NotFound);
+            }
+            Some(std::path::Path::new(&path))
+        };
+        let (commits, next) = log_page(&repo, &start, query.after.map(|ObjectId(id)| id), filter, query.follow, &config)?;
+        (commits, next, rev, path)
+    };
+    let signed = commits.iter()
+        .filter_map(|(id, _)| signature::commit_signature(&repo, *id))
+        .collect();
+    let mut verified = signature::verify_all(signed, &config.signatures).await?;
+    let commits = commits.into_iter()
+        .map(|(id, commit)| with_signature(commit, verified.remove(&id)))
+        .collect::<Vec<_>>();
+
+    Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
+        "repo": {
+            "name": repo_config.title,
+            "slug": repo_name,
+        },
+        "ref": rev,
+        "path": path,
+        "follow": query.follow,
+        "commits": commits,
+        "next": next.map(|id| id.to_string()),
+    }))?))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    /// Branch, tag or commit to search, defaults to HEAD.
+    #[serde(rename = "ref")]
+    rev: Option<String>,
+    /// Treat `q` as a regular expression rather than literal text.
+    #[serde(default)]
+    regex: bool,
+    /// Match case exactly.
+    #[serde(default)]
+    case: bool,
+    /// Only search paths matching this glob.
+    path: Option<String>,
+}
+
+#[tracing::instrument]
+pub(crate) async fn search(Path(repo_name): Path<String>, Query(query): Query<SearchQuery>, Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
+    let template = liquid::ParserBuilder::with_stdlib()
+        .build()?
+        .parse(include_str!("templates/repo/search.html.liquid"))?;
+
+    let (repo_config, repo) = repo_from_name(&repo_name, &config)?;
+    let rev = match query.rev.as_deref().filter(|r| !r.is_empty()) {
+        Some(rev) => rev.to_owned(),
+        None => repo.head()?.shorthand().unwrap_or("HEAD").to_owned(),
+    };
+    let commit_id = match resolve_ref(&repo, &rev)? {
+        (commit, _, path) if path.is_empty() => commit.id(),
+        _ => return Err(GititError::NotFound),
+    };
+    let path = query.path.as_deref().filter(|p| !p.is_empty());
+
+    // Bad patterns are the user's mistake, so they are shown on the page
+    // rather than turned into an error response.
+    let mut error = None;
+    let mut results = None;
+    if !query.q.is_empty() {
+        let pattern = if query.regex { query.q.clone() } else { regex::escape(&query.q) };
+        let pattern = regex::RegexBuilder::new(&pattern)
+            .case_insensitive(!query.case)
+            .build();
+        let glob = path.map(glob::Pattern::new).transpose();
+        match (pattern, glob) {
+            (Err(e), _) => error = Some(format!("Invalid regular expression: {}", e)),
+            (_, Err(e)) => error = Some(format!("Invalid path pattern: {}", e)),
+            (Ok(pattern), Ok(glob)) => {
+                let options = search::Options {
+                    pattern,
+                    path: glob,
+                    max_results: config.web.search_max_results,
+                    deadline: Instant::now() + Duration::from_secs(config.web.search_timeout_secs),
                 };
-                return Err(GititError::Redirect(target));
+                // Searching can take up to the timeout, so keep it off the runtime's workers.
+                results = Some(tokio::task::spawn_blocking(move || {
+                    search::grep_tree(&repo, &repo.find_commit(commit_id)?.tree()?, &options)
+                }).await??);
             }
+        }
+    }
+
+    Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
+        "repo": {
+            "name": repo_config.title,
+            "slug": repo_name,
+        },
+        "query": {
+            "q": query.q,
+            "ref": rev,
+            "regex": query.regex,
+            "case": query.case,
+            "path": path,
+        },
+        "error": error,
+        "searched": results.is_some(),
+        "files": results.as_ref().map(|r| r.files()).unwrap_or_default(),
+        "match_count": results.as_ref().map(|r| r.matches()).unwrap_or_default(),
+        "truncated": results.as_ref().map(|r| r.truncated).unwrap_or_default(),
+        "timed_out": results.as_ref().map(|r| r.timed_out).unwrap_or_default(),
+    }))?))
+}
+
+/// The most commits listed on a compare page, the diff is still complete.
+const COMPARE_COMMIT_LIMIT: usize = 250;
+
+/// The diff layout options of a compare page, the same as a commit page has.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct CompareQuery {
+    #[serde(default)]
+    split: bool,
+    #[serde(default)]
+    collapsed: bool,
+}
 
-            if let Some(subtree) = subtree.to_object(&repo)?.as_tree() {
-               render_tree(&commit.id().to_string(), path, subtree)
+impl CompareQuery {
+    /// A link to this page with `change` applied to the current options.
+    fn href(&self, change: impl FnOnce(&mut CompareQuery)) -> String {
+        let mut query = self.clone();
+        change(&mut query);
+        let params = [("split", query.split), ("collapsed", query.collapsed)].into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| format!("{}=true", name))
+            .collect::<Vec<_>>();
+        format!("?{}", params.join("&"))
+    }
+}
+
+#[tracing::instrument]
+pub(crate) async fn compare(Path((repo_name, spec)): Path<(String, String)>, Query(query): Query<CompareQuery>, Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
+    let template = parser()?
+        .parse(include_str!("templates/repo/compare.html.liquid"))?;
+
+    let (repo_config, repo) = repo_from_name(&repo_name, &config)?;
+    let (base_name, head_name) = spec.trim_start_matches('/').split_once("...").ok_or(GititError::NotFound)?;
+    let resolve = |name: &str| match resolve_ref(&repo, name)? {
+        (commit, _, path) if path.is_empty() => Ok(commit),
+        _ => Err(GititError::NotFound),
+    };
+    let base_commit = resolve(base_name)?;
+    let head_commit = resolve(head_name)?;
+
+    // Like `git diff base...head`, show what head changed since it diverged.
+    let merge_base = repo.merge_base(base_commit.id(), head_commit.id()).ok();
+    let from = match merge_base {
+        Some(id) => repo.find_commit(id)?,
+        None => base_commit.clone(),
+    };
+    let mut diff = repo.diff_tree_to_tree(Some(&from.tree()?), Some(&head_commit.tree()?), None)?;
+    templates::detect_renames(&mut diff, &config.diff)?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.push(head_commit.id())?;
+    revwalk.hide(base_commit.id())?;
+    let mut commits = Vec::new();
+    let mut total = 0;
+    for id in revwalk {
+        let id = id?;
+        total += 1;
+        if commits.len() < COMPARE_COMMIT_LIMIT {
+            commits.push(templates::commit_to_object(&repo, &repo.find_commit(id)?)?);
+        }
+    }
+
+    Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
+        "repo": {
+            "name": repo_config.title,
+            "slug": repo_name,
+        },
+        "base_ref": base_name,
+        "head_ref": head_name,
+        "merge_base": merge_base.map(|id| templates::commit_to_object(&repo, &repo.find_commit(id)?)).transpose()?,
+        "commits": commits,
+        "hidden_commits": total - commits.len(),
+        "split": query.split,
+        "collapsed": query.collapsed,
+        "links": {
+            "unified": query.href(|q| q.split = false),
+            "split": query.href(|q| q.split = true),
+            "collapse": query.href(|q| q.collapsed = true),
+            "expand": query.href(|q| q.collapsed = false),
+        },
+        "files": templates::diff_files(&repo, &diff)?,
+    }))?))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TagsQuery {
+    sort: Option<String>,
+}
+
+#[tracing::instrument]
+pub(crate) async fn tags(Path(repo_name): Path<String>, Query(query): Query<TagsQuery>, Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
+    let template = parser()?
+        .parse(include_str!("templates/repo/tags.html.liquid"))?;
+
+    let (repo_config, repo) = repo_from_name(&repo_name, &config)?;
+
+    let mut tags = Vec::new();
+    let mut signed = Vec::new();
+    for reference in repo.references_glob("refs/tags/*")? {
+        let reference = reference?;
+        let name = match reference.shorthand() {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let commit = match reference.peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => {
+                tracing::warn!("tag {} does not point to a commit", name);
+                continue;
+            }
+        };
+
+        let (time, tagger, message, tag_id) = match reference.peel_to_tag() {
+            Ok(tag) => {
+                let tagger = tag.tagger().map(|sig| liquid::object!({
+                    "name": sig.name().map(|s| s.to_owned()),
+                    "email": sig.email().map(|s| s.to_owned()),
+                }));
+                let time = tag.tagger().map(|sig| sig.when()).unwrap_or_else(|| commit.time());
+                if let Some(tag_signature) = signature::tag_signature(&repo, tag.id()) {
+                    signed.push(tag_signature);
+                }
+                // The signature of a signed tag is appended to its message.
+                let message = tag.message().map(|m| signature::strip_tag_signature(m).trim().to_owned());
+                (time, tagger, message, Some(tag.id()))
+            }
+            Err(_) => (commit.time(), None, None, None),
+        };
+
+        let summary = commit.summary().unwrap_or_default().to_owned();
+        let hash = commit.id().to_string();
+        tags.push((name.clone(), time.seconds(), tag_id, liquid::object!({
+            "name": name,
+            "annotated": message.is_some(),
+            "message": message,
+            "tagger": tagger,
+            "date": templates::format_time(time),
+            "commit": {
+                "hash": hash,
+                "short_hash": hash[..7].to_owned(),
+                "summary": summary,
+            },
+        })));
+    }
+
+    let sort = query.sort.as_deref().unwrap_or("date");
+    match sort {
+        "version" => tags.sort_by(|(a, _, _, _), (b, _, _, _)| compare_versions(b, a)),
+        _ => tags.sort_by(|(_, a, _, _), (_, b, _, _)| b.cmp(a)),
+    }
+
+    let mut verified = signature::verify_all(signed, &config.signatures).await?;
+    let tags = tags.into_iter()
+        .map(|(_, _, tag_id, tag)| with_signature(tag, tag_id.and_then(|id| verified.remove(&id))))
+        .collect::<Vec<_>>();
+
+    Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
+        "repo": {
+            "name": repo_config.title,
+            "slug": repo_name,
+        },
+        "sort": sort,
+        "tags": tags,
+    }))?))
+}
+
+#[tracing::instrument]
+pub(crate) async fn archive(Path((repo_name, rest)): Path<(String, String)>, Extension(config): Extension<SharedConfig>) -> Result<impl IntoResponse> {
+    let config = config.load();
+    let (_, repo) = repo_from_name(&repo_name, &config)?;
+    let rest = rest.strip_suffix(".tar.gz").ok_or(GititError::NotFound)?;
+    let commit_id = {
+        let (commit, _, path) = resolve_ref(&repo, rest)?;
+        if !path.is_empty() {
+            return Err(GititError::NotFound);
+        }
+        commit.id()
+    };
+
+    let ref_name = rest.trim_start_matches('/').replace('/', "-");
+    let slug = repo_name.rsplit('/').next().unwrap_or(&repo_name);
+    let prefix = format!("{}-{}", slug, ref_name);
+
+    // The archive is compressed on a blocking thread and sent as it is
+    // written, so neither a worker nor the whole file is held up by it.
+    let (sender, receiver) = tokio::sync::mpsc::channel(ARCHIVE_CHUNKS_IN_FLIGHT);
+    let archive_prefix = prefix.clone();
+    tokio::task::spawn_blocking(move || {
+        let writer = std::io::BufWriter::with_capacity(ARCHIVE_CHUNK_SIZE, ChannelWriter(sender.clone()));
+        let result = (|| -> Result<()> {
+            let commit = repo.find_commit(commit_id)?;
+            let mut builder = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+            append_tree(&repo, &mut builder, &commit.tree()?, std::path::Path::new(&archive_prefix), commit.time().seconds().max(0) as u64)?;
+            std::io::Write::flush(&mut builder.into_inner()?.finish()?)?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            tracing::error!("failed to build archive {}: {}", archive_prefix, e);
+            // Cut the response short rather than end it as if it were complete.
+            sender.blocking_send(Err(std::io::Error::other(e.to_string()))).ok();
+        }
+    });
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/gzip".to_owned()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.tar.gz\"", prefix)),
+        ],
+        StreamBody::new(body),
+    ))
+}
+
+/// Size of the pieces an archive is sent in.
+const ARCHIVE_CHUNK_SIZE: usize = 64 * 1024;
+/// Pieces that may be waiting for a slow client before compression pauses.
+const ARCHIVE_CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Hands everything written to it to the response body. Writing fails once
+/// the client has gone away, which stops building the archive.
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<Bytes>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn append_tree<W: std::io::Write>(repo: &Repository, builder: &mut tar::Builder<W>, tree: &Tree, prefix: &std::path::Path, mtime: u64) -> Result<()> {
+    for entry in tree.iter() {
+        let name = match entry.name() {
+            Some(name) => name,
+            None => continue,
+        };
+        let path = prefix.join(name);
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(mtime);
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                append_tree(repo, builder, &repo.find_tree(entry.id())?, &path, mtime)?;
+            }
+            Some(git2::ObjectType::Blob) => {
+                let blob = repo.find_blob(entry.id())?;
+                if entry.filemode() == 0o120000 {
+                    let target = String::from_utf8_lossy(blob.content()).into_owned();
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    header.set_mode(0o777);
+                    builder.append_link(&mut header, &path, target)?;
+                } else {
+                    header.set_size(blob.size() as u64);
+                    header.set_mode(if entry.filemode() == 0o100755 { 0o755 } else { 0o644 });
+                    builder.append_data(&mut header, &path, blob.content())?;
+                }
+            }
+            // Submodules aren't part of this repository.
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Serves the exact bytes of a blob. Text is always sent as plain text so a
+/// mirrored `.html` or `.svg` can't run scripts on our origin.
+#[tracing::instrument]
+pub(crate) async fn raw(Path((repo_name, rest)): Path<(String, String)>, if_none_match: Option<TypedHeader<IfNoneMatch>>, Extension(config): Extension<SharedConfig>) -> Result<impl IntoResponse> {
+    let config = config.load();
+    let (_, repo) = repo_from_name(&repo_name, &config)?;
+    let (commit, _, path) = resolve_ref(&repo, &rest)?;
+    let entry = commit.tree()?.get_path(std::path::Path::new(path.trim_start_matches('/'))).map_err(|_| GititError::NotFound)?;
+    let blob = repo.find_blob(entry.id()).map_err(|_| GititError::NotFound)?;
+
+    let etag = format!("{:?}", blob.id().to_string()).parse::<ETag>().unwrap();
+    if let Some(TypedHeader(if_none_match)) = if_none_match {
+        if !if_none_match.precondition_passes(&etag) {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            response.headers_mut().typed_insert(etag);
+            return Ok(response);
+        }
+    }
+
+    let content_type = if blob.is_binary() {
+        safe_mime(mime_guess::from_path(&path).first_or_octet_stream()).to_string()
+    } else {
+        "text/plain; charset=utf-8".to_owned()
+    };
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, blob.size().to_string()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            (header::CONTENT_SECURITY_POLICY, "default-src 'none'; sandbox".to_owned()),
+        ],
+        blob.content().to_owned(),
+    ).into_response();
+    response.headers_mut().typed_insert(etag);
+    Ok(response)
+}
+
+#[tracing::instrument]
+pub(crate) async fn blame(Path((repo_name, rest)): Path<(String, String)>, Extension(config): Extension<SharedConfig>) -> Result<Html<String>> {
+    let config = config.load();
+    let template = liquid::ParserBuilder::with_stdlib()
+        .build()?
+        .parse(include_str!("templates/repo/blame.html.liquid"))?;
+
+    let (repo_config, repo) = repo_from_name(&repo_name, &config)?;
+    // Blaming walks the file's history, which can take a while, so keep it
+    // off the runtime's workers.
+    let (rev, path, lines) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let (commit, rev, path) = resolve_ref(&repo, &rest)?;
+        let file_path = std::path::Path::new(path.trim_start_matches('/'));
+        let entry = commit.tree()?.get_path(file_path).map_err(|_| GititError::NotFound)?;
+        let blob = repo.find_blob(entry.id()).map_err(|_| GititError::NotFound)?;
+        if blob.is_binary() {
+            return Err(GititError::NotFound);
+        }
+
+        let content = String::from_utf8_lossy(blob.content());
+        let extension = file_path.extension().map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "txt".to_owned());
+        let highlighted = templates::syntax_highlight_lines(&extension, &content)?;
+
+        let mut options = BlameOptions::new();
+        options.newest_commit(commit.id());
+        let blame = repo.blame_file(file_path, Some(&mut options))?;
+
+        let mut lines = Vec::with_capacity(highlighted.len());
+        for (number, html) in highlighted.into_iter().enumerate() {
+            let hunk = blame.get_line(number + 1);
+            let first_in_hunk = hunk.as_ref().map(|h| h.final_start_line() == number + 1).unwrap_or(false);
+            let info = match hunk.filter(|_| first_in_hunk) {
+                Some(hunk) => {
+                    let hunk_commit = repo.find_commit(hunk.final_commit_id())?;
+                    let hash = hunk_commit.id().to_string();
+                    // Blaming the parent at the path the line had in this commit
+                    // shows where the line came from before this change.
+                    let parent = hunk_commit.parent_ids().next().map(|id| id.to_string());
+                    let orig_path = hunk.path().map(|p| p.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| file_path.to_string_lossy().into_owned());
+                    Some(liquid::object!({
+                        "hash": hash,
+                        "short_hash": hash[..7].to_owned(),
+                        "summary": hunk_commit.summary().unwrap_or_default().to_owned(),
+                        "author": hunk.final_signature().name().map(|s| s.to_owned()),
+                        "date": templates::format_time(hunk.final_signature().when()),
+                        "parent": parent,
+                        "orig_url_path": encode_path(&orig_path),
+                        "orig_path": orig_path,
+                    }))
+                }
+                None => None,
+            };
+            lines.push(liquid::object!({
+                "number": number + 1,
+                "html": html,
+                "hunk": info,
+            }));
+        }
+        Ok((rev, path, lines))
+    }).await??;
+
+    Ok(Html(template.render(&liquid::object!({
+        "base": config.server.base_path,
+        "repo": {
+            "name": repo_config.title,
+            "slug": repo_name,
+        },
+        "rev": rev,
+        "url_path": encode_path(&path),
+        "path": path,
+        "lines": lines,
+    }))?))
+}
+
+/// Adds the signature badge data to a commit object.
+fn with_signature(mut object: liquid::Object, verification: Option<signature::Verification>) -> liquid::Object {
+    let value = verification.map(|v| liquid::model::Value::Object(v.to_object())).unwrap_or(liquid::model::Value::Nil);
+    object.insert("signature".into(), value);
+    object
+}
+
+/// Splits `rest` (`/<ref>/<path>`) into the commit that a branch, tag or
+/// commit id points to, the ref name itself, and the path within it. Ref
+/// names may contain slashes, so the longest prefix that names a ref wins.
+fn resolve_ref<'repo>(repo: &'repo Repository, rest: &str) -> Result<(Commit<'repo>, String, String)> {
+    let segments = rest.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    for i in (1..=segments.len()).rev() {
+        let name = segments[..i].join("/");
+        let commit = match repo.resolve_reference_from_short_name(&name) {
+            Ok(reference) => match reference.peel_to_commit() {
+                Ok(commit) => commit,
+                // A ref to a tree or blob can't be browsed, but a shorter
+                // prefix might still name a branch.
+                Err(_) => continue,
+            },
+            Err(_) => match Oid::from_str(&name).ok().filter(|_| name.len() == 40) {
+                Some(oid) => match repo.find_commit(oid) {
+                    Ok(commit) => commit,
+                    Err(_) => continue,
+                },
+                None => continue,
+            },
+        };
+        let path = if i == segments.len() {
+            String::new()
+        } else {
+            format!("/{}", segments[i..].join("/"))
+        };
+        return Ok((commit, name, path));
+    }
+    Err(GititError::NotFound)
+}
+
+/// Where a file or directory view was reached from, for building links to
+/// related pages.
+#[derive(Debug)]
+struct Location<'a> {
+    base: &'a str,
+    slug: &'a str,
+    /// The branch, tag or commit id in the URL.
+    rev: String,
+    commit: String,
+}
+
+impl<'a> Location<'a> {
+    fn new(base: &'a str, slug: &'a str, rev: String, commit: &Commit) -> Self {
+        Self { base, slug, rev, commit: commit.id().to_string() }
+    }
+
+    fn to_object(&self) -> liquid::Object {
+        liquid::object!({
+            "base": self.base,
+            "repo": {
+                "slug": self.slug,
+            },
+            "rev": self.rev,
+            "commit": {
+                "hash": self.commit,
+            },
+        })
+    }
+}
+
+/// Renders the file or directory at `path` in `commit`. Directories are
+/// redirected to a URL with a trailing slash so relative links work.
+fn browse(location: &Location, repo: &Repository, commit: &Commit, path: String, uri: &Uri) -> Result<HtmlOrRaw> {
+    let base = location.base;
+    let tree = commit.tree()?;
+
+    if path.is_empty() || !path.ends_with('/') {
+        let is_tree = path.len() <= 1 || tree.get_path(std::path::Path::new(&path[1..])).map_err(|_| GititError::NotFound)?.kind() == Some(git2::ObjectType::Tree);
+        if is_tree {
+            // `uri` has the base path stripped by the router, so add it back.
+            let target = if let Some(query) = uri.query() {
+                format!("{}{}/?{}", base, uri.path(), query)
+            } else {
+                format!("{}{}/", base, uri.path())
+            };
+            return Err(GititError::Redirect(target));
+        }
+    }
+
+    if path.len() <= 1 {
+        return render_tree(location, repo, path, &tree);
+    };
+
+    let subtree = tree.get_path(std::path::Path::new(&path[1..])).map_err(|_| GititError::NotFound)?;
+
+    match subtree.kind().unwrap() {
+        git2::ObjectType::Tree => {
+            if let Some(subtree) = subtree.to_object(repo)?.as_tree() {
+               render_tree(location, repo, path, subtree)
             } else {
                 Err(GititError::NotFound)
             }
         },
         git2::ObjectType::Blob => {
-            if let Some(blob) = subtree.to_object(&repo)?.as_blob() {
-                render_file(&commit.id().to_string(), path, &blob)
+            if let Some(blob) = subtree.to_object(repo)?.as_blob() {
+                render_file(location, path, blob)
             } else {
                 Err(GititError::NotFound)
             }
@@ -145,7 +1119,7 @@ pub(crate) async fn commit_tree(Path((repo_name, ObjectId(commit), path)): Path<
     }
 }
 
-fn render_file(commit: &str, path: String, blob: &Blob) -> Result<HtmlOrRaw> {
+fn render_file(location: &Location, path: String, blob: &Blob) -> Result<HtmlOrRaw> {
     let template = liquid::ParserBuilder::with_stdlib()
         .build()?
         .parse(include_str!("templates/repo/text_file.html.liquid"))?;
@@ -153,23 +1127,38 @@ fn render_file(commit: &str, path: String, blob: &Blob) -> Result<HtmlOrRaw> {
     if blob.is_binary() {
         Ok(HtmlOrRaw::Raw(safe_mime(mime_guess::from_path(path).first_or_octet_stream()).to_string(), blob.content().to_owned()))
     } else {
-        let string_content = std::str::from_utf8(blob.content()).unwrap().to_owned();
+        let string_content = String::from_utf8_lossy(blob.content());
         let extension = std::path::Path::new(&path).extension().map(|s| s.to_string_lossy().to_string())
             .unwrap_or_else(|| "txt".to_owned());
-        Ok(HtmlOrRaw::Html(template.render(&liquid::object!({
-            "commit": {
-                "hash": commit,
-            },
-            "file": {
-                "path": path,
-                "content": templates::syntax_highlight(&extension, &string_content)?,
-            },
-        }))?))
+        // Lines are rendered individually so they can be linked to as `#L<n>`.
+        let lines = templates::syntax_highlight_lines(&extension, &string_content)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, html)| liquid::object!({
+                "number": i + 1,
+                "html": html,
+            }))
+            .collect::<Vec<_>>();
+        let mut globals = location.to_object();
+        globals.insert("file".into(), liquid::model::Value::Object(liquid::object!({
+            "url_path": encode_path(&path),
+            "path": path,
+            "lines": lines,
+        })));
+        Ok(HtmlOrRaw::Html(template.render(&globals)?))
     }
 }
 
-#[tracing::instrument]
-fn render_tree(commit: &str, path: String, subtree: &Tree<'_>) -> Result<HtmlOrRaw> {
+fn readme_to_object(repo: &Repository, tree: &Tree<'_>, links: &readme::Links) -> Option<liquid::Object> {
+    let (name, blob) = readme::find(repo, tree)?;
+    Some(liquid::object!({
+        "html": readme::render(&name, blob.content(), links),
+        "name": name,
+    }))
+}
+
+#[tracing::instrument(skip(repo))]
+fn render_tree(location: &Location, repo: &Repository, path: String, subtree: &Tree<'_>) -> Result<HtmlOrRaw> {
     let template = liquid::ParserBuilder::with_stdlib()
         .build()?
         .parse(include_str!("templates/repo/commit_tree.html.liquid"))?;
@@ -187,11 +1176,12 @@ fn render_tree(commit: &str, path: String, subtree: &Tree<'_>) -> Result<HtmlOrR
             },
         }));
     }
-    Ok(HtmlOrRaw::Html(template.render(&liquid::object!({
-        "commit": {
-            "hash": commit,
-        },
-        "path": path,
-        "files": files,
-    }))?))
+    let links = readme::Links { base: location.base, slug: location.slug, rev: &location.rev, dir: &path };
+    let readme = readme_to_object(repo, subtree, &links);
+    let mut globals = location.to_object();
+    globals.insert("url_path".into(), liquid::model::Value::scalar(encode_path(&path)));
+    globals.insert("path".into(), liquid::model::Value::scalar(path));
+    globals.insert("readme".into(), readme.map(liquid::model::Value::Object).unwrap_or(liquid::model::Value::Nil));
+    globals.insert("files".into(), liquid::model::Value::array(files.into_iter().map(liquid::model::Value::Object)));
+    Ok(HtmlOrRaw::Html(template.render(&globals)?))
 }
@@ -0,0 +1,222 @@
+use std::{ops::Range, time::Instant};
+
+use git2::{ObjectType, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use regex::Regex;
+
+use crate::{errors::Result, utils::encode_path};
+
+/// Lines of context shown around each match.
+const CONTEXT: usize = 2;
+/// Blobs larger than this are skipped, they are rarely source code.
+pub(crate) const MAX_BLOB_SIZE: usize = 1024 * 1024;
+/// Keeps `*` within a directory, the way the index search treats path globs.
+const PATH_MATCH: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+pub(crate) struct Options {
+    pub pattern: Regex,
+    /// Only search files whose path matches this glob.
+    pub path: Option<glob::Pattern>,
+    pub max_results: usize,
+    pub deadline: Instant,
+}
+
+struct Line {
+    number: usize,
+    text: String,
+    /// Byte ranges of `text` that matched.
+    matches: Vec<Range<usize>>,
+}
+
+struct FileMatches {
+    path: String,
+    /// Runs of consecutive lines: matches with their context.
+    groups: Vec<Vec<Line>>,
+}
+
+#[derive(Default)]
+pub(crate) struct Results {
+    files: Vec<FileMatches>,
+    matches: usize,
+    /// The search stopped at `max_results`.
+    pub truncated: bool,
+    /// The search stopped at the deadline.
+    pub timed_out: bool,
+}
+
+/// Searches the text blobs of `tree` line by line, stopping at the result
+/// limit or the deadline, whichever comes first.
+pub(crate) fn grep_tree(repo: &Repository, tree: &Tree, options: &Options) -> Result<Results> {
+    let mut results = Results::default();
+    let mut error = None;
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if Instant::now() > options.deadline {
+            results.timed_out = true;
+            return TreeWalkResult::Abort;
+        }
+        if entry.kind() != Some(ObjectType::Blob) {
+            return TreeWalkResult::Ok;
+        }
+        let path = format!("{}{}", root, entry.name().unwrap_or_default());
+        if !options.path.as_ref().map(|p| p.matches_with(&path, PATH_MATCH)).unwrap_or(true) {
+            return TreeWalkResult::Ok;
+        }
+        let blob = match repo.find_blob(entry.id()) {
+            Ok(blob) => blob,
+            Err(e) => {
+                error = Some(e);
+                return TreeWalkResult::Abort;
+            }
+        };
+        if blob.is_binary() || blob.size() > MAX_BLOB_SIZE {
+            return TreeWalkResult::Ok;
+        }
+
+        let remaining = options.max_results - results.matches;
+        let (groups, matches) = grep_text(&String::from_utf8_lossy(blob.content()), &options.pattern, remaining);
+        if matches > 0 {
+            results.matches += matches;
+            results.files.push(FileMatches { path, groups });
+        }
+        if results.matches >= options.max_results {
+            results.truncated = true;
+            return TreeWalkResult::Abort;
+        }
+        TreeWalkResult::Ok
+    })?;
+    match error {
+        Some(e) => Err(e.into()),
+        None => Ok(results),
+    }
+}
+
+/// Finds up to `limit` matching lines in `text` and groups them with their
+/// surrounding context, merging groups that touch.
+fn grep_text(text: &str, pattern: &Regex, limit: usize) -> (Vec<Vec<Line>>, usize) {
+    let lines = text.lines().collect::<Vec<_>>();
+    let matching = lines.iter().enumerate()
+        .filter(|(_, line)| pattern.is_match(line))
+        .map(|(i, _)| i)
+        .take(limit)
+        .collect::<Vec<_>>();
+
+    let mut groups: Vec<Vec<Line>> = Vec::new();
+    let mut group_end = 0;
+    for &i in &matching {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(lines.len());
+        if groups.is_empty() || start > group_end {
+            groups.push(Vec::new());
+            group_end = start;
+        }
+        let group = groups.last_mut().unwrap();
+        for (n, line) in lines.iter().enumerate().take(end).skip(group_end.max(start)) {
+            group.push(Line {
+                number: n + 1,
+                text: line.to_string(),
+                matches: pattern.find_iter(line).map(|m| m.range()).filter(|r| !r.is_empty()).collect(),
+            });
+        }
+        group_end = group_end.max(end);
+    }
+    (groups, matching.len())
+}
+
+impl Line {
+    fn html(&self) -> String {
+        let mut html = String::new();
+        let mut last = 0;
+        for range in &self.matches {
+            html.push_str(&html_escape::encode_text(&self.text[last..range.start]));
+            html.push_str("<mark>");
+            html.push_str(&html_escape::encode_text(&self.text[range.clone()]));
+            html.push_str("</mark>");
+            last = range.end;
+        }
+        html.push_str(&html_escape::encode_text(&self.text[last..]));
+        html
+    }
+}
+
+impl Results {
+    pub(crate) fn files(&self) -> Vec<liquid::Object> {
+        self.files.iter()
+            .map(|file| liquid::object!({
+                "path": file.path,
+                "url_path": encode_path(&file.path),
+                "groups": file.groups.iter()
+                    .map(|group| group.iter()
+                        .map(|line| liquid::object!({
+                            "number": line.number,
+                            "html": line.html(),
+                            "is_match": !line.matches.is_empty(),
+                        }))
+                        .collect::<Vec<_>>())
+                    .collect::<Vec<_>>(),
+            }))
+            .collect()
+    }
+
+    pub(crate) fn matches(&self) -> usize {
+        self.matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(groups: &[Vec<Line>]) -> Vec<Vec<usize>> {
+        groups.iter().map(|group| group.iter().map(|line| line.number).collect()).collect()
+    }
+
+    fn text(matching: &[usize]) -> String {
+        (1..=20).map(|n| if matching.contains(&n) { format!("match {}\n", n) } else { format!("line {}\n", n) }).collect()
+    }
+
+    #[test]
+    fn matches_get_context_on_both_sides() {
+        let (groups, matches) = grep_text(&text(&[10]), &Regex::new("match").unwrap(), 100);
+        assert_eq!(matches, 1);
+        assert_eq!(numbers(&groups), vec![vec![8, 9, 10, 11, 12]]);
+        assert_eq!(groups[0][2].matches, vec![0..5]);
+        assert!(groups[0][1].matches.is_empty());
+    }
+
+    #[test]
+    fn overlapping_and_touching_groups_merge() {
+        let pattern = Regex::new("match").unwrap();
+        let (groups, _) = grep_text(&text(&[5, 8]), &pattern, 100);
+        assert_eq!(numbers(&groups), vec![vec![3, 4, 5, 6, 7, 8, 9, 10]]);
+        let (groups, _) = grep_text(&text(&[5, 10]), &pattern, 100);
+        assert_eq!(numbers(&groups), vec![(3..=12).collect::<Vec<_>>()]);
+        let (groups, _) = grep_text(&text(&[5, 11]), &pattern, 100);
+        assert_eq!(numbers(&groups), vec![vec![3, 4, 5, 6, 7], vec![9, 10, 11, 12, 13]]);
+    }
+
+    #[test]
+    fn context_stops_at_the_edges_of_the_file() {
+        let (groups, _) = grep_text(&text(&[1, 20]), &Regex::new("match").unwrap(), 100);
+        assert_eq!(numbers(&groups), vec![vec![1, 2, 3], vec![18, 19, 20]]);
+    }
+
+    #[test]
+    fn matching_stops_at_the_limit() {
+        let (groups, matches) = grep_text(&text(&[2, 4, 6]), &Regex::new("match").unwrap(), 2);
+        assert_eq!(matches, 2);
+        assert_eq!(numbers(&groups), vec![vec![1, 2, 3, 4, 5, 6]]);
+    }
+
+    #[test]
+    fn path_globs_keep_stars_within_a_directory() {
+        let glob = glob::Pattern::new("src/*.rs").unwrap();
+        assert!(glob.matches_with("src/main.rs", PATH_MATCH));
+        assert!(!glob.matches_with("src/routes/repo.rs", PATH_MATCH));
+        let glob = glob::Pattern::new("src/**/*.rs").unwrap();
+        assert!(glob.matches_with("src/main.rs", PATH_MATCH));
+        assert!(glob.matches_with("src/routes/repo.rs", PATH_MATCH));
+    }
+}
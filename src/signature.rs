@@ -0,0 +1,292 @@
+use std::{collections::HashMap, fs, io::Write, path::PathBuf, process::{Command, Stdio}, sync::{Mutex, OnceLock}, thread, time::{Duration, Instant, SystemTime}};
+
+use git2::{Oid, Repository};
+
+use crate::{config::SignatureConfig, errors::Result};
+
+/// Cached verifications are dropped wholesale past this many entries.
+const CACHE_LIMIT: usize = 10_000;
+
+/// `gpgv` and `ssh-keygen` are killed if they take longer than this.
+const HELPER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The lines git starts a signature with, for each kind of signature.
+const SIGNATURE_HEADERS: &[(&[u8], Kind)] = &[
+    (b"-----BEGIN PGP SIGNATURE-----", Kind::Gpg),
+    (b"-----BEGIN PGP MESSAGE-----", Kind::Gpg),
+    (b"-----BEGIN SSH SIGNATURE-----", Kind::Ssh),
+    (b"-----BEGIN SIGNED MESSAGE-----", Kind::X509),
+];
+
+/// The keys a verification was made with, so cached results are redone when
+/// a keyring changes on disk.
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>)>;
+
+type Cache = HashMap<Oid, (Fingerprint, Verification)>;
+
+static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Gpg,
+    Ssh,
+    X509,
+}
+
+#[derive(Clone, Debug)]
+enum State {
+    Good { signer: String },
+    Bad,
+    UnknownKey,
+    Unverified { reason: String },
+}
+
+/// The outcome of checking a signed commit or tag.
+#[derive(Clone, Debug)]
+pub(crate) struct Verification {
+    kind: Kind,
+    state: State,
+}
+
+impl Verification {
+    pub(crate) fn to_object(&self) -> liquid::Object {
+        let kind = match self.kind {
+            Kind::Gpg => "GPG",
+            Kind::Ssh => "SSH",
+            Kind::X509 => "X.509",
+        };
+        let (state, signer, message) = match &self.state {
+            State::Good { signer } => ("verified", Some(signer.clone()), format!("Good {} signature from {}", kind, signer)),
+            State::Bad => ("bad", None, format!("Bad {} signature", kind)),
+            State::UnknownKey => ("unknown", None, format!("{} signature from an unknown key", kind)),
+            State::Unverified { reason } => ("unverified", None, format!("{} signature not verified: {}", kind, reason)),
+        };
+        liquid::object!({
+            "state": state,
+            "kind": kind,
+            "signer": signer,
+            "message": message,
+        })
+    }
+}
+
+/// A signature and the data it signs, copied out of the repo so they can be
+/// checked on another thread.
+pub(crate) struct Signed {
+    id: Oid,
+    signature: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Reads the signature of a commit, if it has one.
+pub(crate) fn commit_signature(repo: &Repository, id: Oid) -> Option<Signed> {
+    let (signature, data) = repo.extract_signature(&id, None).ok()?;
+    Some(Signed { id, signature: signature.to_vec(), data: data.to_vec() })
+}
+
+/// Reads the signature of an annotated tag, if it has one. Unlike commits,
+/// tags carry their signature at the end of the message, starting at the last
+/// line that opens a signature, as git splits them.
+pub(crate) fn tag_signature(repo: &Repository, id: Oid) -> Option<Signed> {
+    let odb = repo.odb().ok()?;
+    let object = odb.read(id).ok()?;
+    let raw = object.data();
+    let (data, signature) = raw.split_at(signature_start(raw)?);
+    Some(Signed { id, signature: signature.to_vec(), data: data.to_vec() })
+}
+
+/// The message of an annotated tag without the signature appended to it.
+pub(crate) fn strip_tag_signature(message: &str) -> &str {
+    match signature_start(message.as_bytes()) {
+        Some(start) => &message[..start],
+        None => message,
+    }
+}
+
+/// Where the signature at the end of a tag starts: the last line that opens
+/// one of the known signature blocks.
+fn signature_start(raw: &[u8]) -> Option<usize> {
+    (0..raw.len()).rev()
+        .find(|&start| (start == 0 || raw[start - 1] == b'\n') && signature_kind(&raw[start..]).is_some())
+}
+
+/// Checks signatures on a blocking thread, since each one that isn't cached
+/// runs `gpgv` or `ssh-keygen`.
+pub(crate) async fn verify_all(signed: Vec<Signed>, config: &SignatureConfig) -> Result<HashMap<Oid, Verification>> {
+    if signed.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let config = config.clone();
+    let verified = tokio::task::spawn_blocking(move || {
+        signed.into_iter()
+            .map(|signed| (signed.id, cached(signed.id, &config, || verify(&signed.signature, &signed.data, &config))))
+            .collect()
+    }).await?;
+    Ok(verified)
+}
+
+fn signature_kind(signature: &[u8]) -> Option<Kind> {
+    SIGNATURE_HEADERS.iter()
+        .find(|(header, _)| signature.starts_with(header))
+        .map(|(_, kind)| *kind)
+}
+
+fn cached(id: Oid, config: &SignatureConfig, verify: impl FnOnce() -> Verification) -> Verification {
+    let fingerprint = config.gpg_keyrings.iter().chain(&config.ssh_allowed_signers)
+        .map(|path| (path.clone(), fs::metadata(path).and_then(|m| m.modified()).ok()))
+        .collect::<Fingerprint>();
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some((cached_fingerprint, verification)) = cache.lock().unwrap().get(&id) {
+        if *cached_fingerprint == fingerprint {
+            return verification.clone();
+        }
+    }
+
+    let verification = verify();
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= CACHE_LIMIT {
+        cache.clear();
+    }
+    cache.insert(id, (fingerprint, verification.clone()));
+    verification
+}
+
+fn verify(signature: &[u8], data: &[u8], config: &SignatureConfig) -> Verification {
+    // Commit signatures come from a header of their own, so anything git
+    // doesn't recognize is left to the X.509 tooling, as git does.
+    let kind = signature_kind(signature).unwrap_or(Kind::X509);
+    let state = match kind {
+        Kind::Gpg => verify_gpg(signature, data, config),
+        Kind::Ssh => verify_ssh(signature, data, config),
+        Kind::X509 => Ok(State::Unverified { reason: "X.509 signatures are not supported".to_owned() }),
+    };
+    let state = state.unwrap_or_else(|e| {
+        tracing::warn!("failed to verify {:?} signature: {}", kind, e);
+        State::Unverified { reason: e.to_string() }
+    });
+    Verification { kind, state }
+}
+
+/// Runs `command` with `data` on stdin and returns whether it succeeded and
+/// its stdout. The command is killed if it runs past `HELPER_TIMEOUT`.
+fn run(command: &mut Command, data: &[u8]) -> std::io::Result<(bool, String)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Feed and drain the pipes on their own threads, so a tool that stops
+    // reading or writing can't keep us from noticing it hung.
+    let mut stdin = child.stdin.take().unwrap();
+    let data = data.to_vec();
+    // The tools may exit before reading all of stdin, e.g. on a malformed
+    // signature, so a broken pipe here is not an error.
+    let writer = thread::spawn(move || stdin.write_all(&data).ok());
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        std::io::Read::read_to_end(&mut stdout, &mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + HELPER_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait()?;
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{:?} timed out", command.get_program())));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    writer.join().ok();
+    let output = reader.join().unwrap_or_else(|_| Ok(Vec::new()))?;
+    Ok((status.success(), String::from_utf8_lossy(&output).into_owned()))
+}
+
+fn verify_gpg(signature: &[u8], data: &[u8], config: &SignatureConfig) -> std::io::Result<State> {
+    if config.gpg_keyrings.is_empty() {
+        return Ok(State::Unverified { reason: "no GPG keyring configured".to_owned() });
+    }
+    let mut signature_file = tempfile::NamedTempFile::new()?;
+    signature_file.write_all(signature)?;
+
+    let mut command = Command::new("gpgv");
+    command.arg("--status-fd=1");
+    for keyring in &config.gpg_keyrings {
+        command.arg("--keyring").arg(keyring);
+    }
+    command.arg(signature_file.path()).arg("-");
+    let (success, status) = run(&mut command, data)?;
+
+    // Only a clean exit that reported both a good and a valid signature counts.
+    let mut signer = None;
+    let mut valid = false;
+    let mut error = false;
+    for line in status.lines().filter_map(|line| line.strip_prefix("[GNUPG:] ")) {
+        let mut fields = line.splitn(3, ' ');
+        match fields.next() {
+            Some("GOODSIG") => signer = Some(fields.nth(1).unwrap_or_default().to_owned()),
+            Some("VALIDSIG") => valid = true,
+            Some("ERRSIG") => error = true,
+            Some("BADSIG") => return Ok(State::Bad),
+            Some("EXPSIG") => return Ok(State::Unverified { reason: "the signature has expired".to_owned() }),
+            Some("EXPKEYSIG") => return Ok(State::Unverified { reason: "the key has expired".to_owned() }),
+            Some("REVKEYSIG") => return Ok(State::Unverified { reason: "the key has been revoked".to_owned() }),
+            Some("NO_PUBKEY") => return Ok(State::UnknownKey),
+            _ => {}
+        }
+    }
+    match signer {
+        Some(signer) if success && valid && !error => Ok(State::Good { signer }),
+        _ if error => Ok(State::Unverified { reason: "gpgv could not check the signature".to_owned() }),
+        _ => Ok(State::Unverified { reason: "gpgv gave no verdict".to_owned() }),
+    }
+}
+
+fn verify_ssh(signature: &[u8], data: &[u8], config: &SignatureConfig) -> std::io::Result<State> {
+    let allowed_signers = match &config.ssh_allowed_signers {
+        Some(allowed_signers) => allowed_signers,
+        None => return Ok(State::Unverified { reason: "no SSH allowed_signers file configured".to_owned() }),
+    };
+    let mut signature_file = tempfile::NamedTempFile::new()?;
+    signature_file.write_all(signature)?;
+
+    let (found, principals) = run(Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-f"])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(signature_file.path()), &[])?;
+    let principal = match principals.lines().next() {
+        Some(principal) if found => principal.to_owned(),
+        _ => return Ok(State::UnknownKey),
+    };
+
+    // Git signs both commits and tags in the "git" namespace.
+    let (verified, _) = run(Command::new("ssh-keygen")
+        .args(["-Y", "verify", "-n", "git", "-f"])
+        .arg(allowed_signers)
+        .arg("-I")
+        .arg(&principal)
+        .arg("-s")
+        .arg(signature_file.path()), data)?;
+    if verified {
+        Ok(State::Good { signer: principal })
+    } else {
+        Ok(State::Bad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_tag_signature;
+
+    #[test]
+    fn only_the_trailing_signature_is_stripped() {
+        let message = "Release 1.0\n\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n-----BEGIN PGP SIGNATURE-----\niQEz\n-----END PGP SIGNATURE-----\n";
+        assert_eq!(strip_tag_signature(message), "Release 1.0\n\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n");
+        assert_eq!(strip_tag_signature("Release 1.0\n"), "Release 1.0\n");
+    }
+}
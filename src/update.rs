@@ -2,7 +2,7 @@ use std::{path::{PathBuf, Path}, io::{self, Write}, cell::RefCell, fs};
 
 use git2::{Progress, RemoteCallbacks, FetchOptions, build::RepoBuilder, AutotagOption, Repository};
 
-use crate::{config::{RepoConfig, Config}, errors::Result};
+use crate::{config::{RepoConfig, Config}, errors::Result, index::Indexer, verify};
 
 // Most of this clone/fetch code is copied from the git2-rs examples
 
@@ -18,11 +18,7 @@ fn print(state: &mut State) {
     let stats = state.progress.as_ref().unwrap();
     let network_pct = (100 * stats.received_objects()) / stats.total_objects();
     let index_pct = (100 * stats.indexed_objects()) / stats.total_objects();
-    let co_pct = if state.total > 0 {
-        (100 * state.current) / state.total
-    } else {
-        0
-    };
+    let co_pct = (100 * state.current).checked_div(state.total).unwrap_or(0);
     let kbytes = stats.received_bytes() / 1024;
     if stats.received_objects() == stats.total_objects() {
         if !state.newline {
@@ -71,7 +67,7 @@ fn clone_repository(repo_config: &RepoConfig, path: &Path) -> Result<Repository>
     cb.transfer_progress(|stats| {
         let mut state = state.borrow_mut();
         state.progress = Some(stats.to_owned());
-        print(&mut *state);
+        print(&mut state);
         true
     });
 
@@ -208,20 +204,44 @@ fn update_head(config: &RepoConfig, repo: &Repository) -> Result<()> {
     Ok(())
 }
 
+/// Searching is secondary to mirroring, so index failures are logged rather
+/// than stopping the remaining mirrors from being fetched.
+fn update_index(indexer: &mut Option<Indexer>, update: impl FnOnce(&mut Indexer) -> Result<()>) {
+    if let Some(indexer) = indexer {
+        if let Err(e) = update(indexer) {
+            tracing::error!("failed to update the search index: {}", e);
+        }
+    }
+}
+
 pub(crate) fn update_repos(config: Config) -> Result<()> {
-    for (slug, repo_config) in config.repos {
-        let mut path = PathBuf::new();
-        path.push("repos");
-        path.push(format!("{}.git", slug));
+    let mut indexer = Indexer::open(&config)
+        .map_err(|e| tracing::error!("cannot open the search index, mirrors won't be indexed: {}", e))
+        .ok();
+    update_index(&mut indexer, |indexer| indexer.retain(&config));
+
+    for (slug, repo_config) in &config.repos {
+        let path = config.repo_path(slug, repo_config);
         let repo = if !path.exists() {
             tracing::info!("Cloning {} into {:?}...", repo_config.url, &path);
-            clone_repository(&repo_config, &path)?
+            clone_repository(repo_config, &path)?
         } else {
             tracing::info!("Fetching {} in {:?}...", repo_config.url, &path);
-            fetch_repo(&repo_config, &path)?
+            fetch_repo(repo_config, &path)?
         };
+        if config.update.verify {
+            let result = verify::verify_repo(&repo);
+            verify::record_status(&repo, &result)?;
+            if let Err(e) = result {
+                tracing::error!("{} failed verification, it will not be served: {}", slug, e);
+                update_index(&mut indexer, |indexer| indexer.remove_repo(slug));
+                continue;
+            }
+        }
         update_refs_info(&repo)?;
-        update_head(&repo_config, &repo)?;
+        update_head(repo_config, &repo)?;
+
+        update_index(&mut indexer, |indexer| indexer.update_repo(slug, &repo_config.head, &repo));
     }
 
     Ok(())
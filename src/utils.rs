@@ -1,9 +1,23 @@
+use std::cmp::Ordering;
+
 use axum::{response::{IntoResponse, Html}, http::header};
 use git2::Oid;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::{Deserializer, de::Visitor};
 
+/// Characters that don't need escaping in a single URL path segment.
+pub const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+/// Percent-encodes each segment of `path` for use in a URL, keeping the
+/// slashes between them.
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 pub enum HtmlOrRaw {
-    String(String),
     Html(String),
     Raw(String, Vec<u8>),
 }
@@ -11,7 +25,6 @@ pub enum HtmlOrRaw {
 impl IntoResponse for HtmlOrRaw {
     fn into_response(self) -> axum::response::Response {
         match self {
-            HtmlOrRaw::String(s) => s.into_response(),
             HtmlOrRaw::Html(s) => Html(s).into_response(),
             HtmlOrRaw::Raw(content_type, data) => ([(header::CONTENT_TYPE, content_type)], data).into_response(),
         }
@@ -20,13 +33,48 @@ impl IntoResponse for HtmlOrRaw {
 
 pub fn safe_mime(mime: mime_guess::Mime) -> mime_guess::Mime {
     if mime.essence_str().starts_with("application/") {
-        return mime::APPLICATION_OCTET_STREAM;
+        mime::APPLICATION_OCTET_STREAM
     } else {
-        return mime;
+        mime
+    }
+}
+
+/// Compares two tag names the way people expect versions to sort, so that
+/// `v1.10` comes after `v1.9`. Runs of digits compare numerically and
+/// everything else compares as text.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn chunks(s: &str) -> Vec<(bool, &str)> {
+        let s = s.strip_prefix('v').unwrap_or(s);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            if i > start && c.is_ascii_digit() != s[start..].starts_with(|c: char| c.is_ascii_digit()) {
+                chunks.push(&s[start..i]);
+                start = i;
+            }
+        }
+        chunks.push(&s[start..]);
+        chunks.into_iter()
+            .map(|chunk| (chunk.starts_with(|c: char| c.is_ascii_digit()), chunk))
+            .collect()
+    }
+
+    for (a, b) in chunks(a).into_iter().zip(chunks(b)) {
+        let ordering = match (a, b) {
+            ((true, a), (true, b)) => {
+                let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+                a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+            }
+            ((_, a), (_, b)) => a.cmp(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
     }
+    a.len().cmp(&b.len())
 }
 
-#[derive(serde::Deserialize)]
+#[derive(Debug, serde::Deserialize)]
 pub struct ObjectId(#[serde(deserialize_with = "deserialize_oid")] pub Oid);
 
 // deserialize_with
@@ -49,11 +97,11 @@ impl<'de> Visitor<'de> for OidVisitor {
 }
 
 pub mod templates {
-    use git2::{Commit, Repository, DiffFormat, DiffOptions, Diff};
-    use syntect::{parsing::SyntaxSet, highlighting::ThemeSet};
-    use std::fmt::Write;
+    use git2::{Commit, Repository, DiffFindOptions, DiffFormat, DiffOptions, Diff, Delta, Patch};
+    use std::{collections::HashMap, ops::Range, path::PathBuf};
+    use syntect::{parsing::{SyntaxReference, SyntaxSet}, highlighting::{Style, Theme, ThemeSet}, easy::HighlightLines, html::{styled_line_to_highlighted_html, IncludeBackground}, util::LinesWithEndings};
 
-    use crate::errors::Result;
+    use crate::{config::DiffConfig, errors::Result};
 
     #[tracing::instrument]
     pub fn syntax_highlight(extension: &str, code: &str) -> Result<String> {
@@ -66,13 +114,41 @@ pub mod templates {
         Ok(html)
     }
 
+    /// Formats a git timestamp in the timezone it was recorded in.
+    pub fn format_time(time: git2::Time) -> String {
+        let format = time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]");
+        let offset = time::UtcOffset::from_whole_seconds(time.offset_minutes() * 60).unwrap_or(time::UtcOffset::UTC);
+        time::OffsetDateTime::from_unix_timestamp(time.seconds())
+            .map(|t| t.to_offset(offset).format(&format).unwrap())
+            .unwrap_or_default()
+    }
+
+    /// Like [`syntax_highlight`], but returns each line separately so they
+    /// can be laid out individually, e.g. next to blame information.
+    #[tracing::instrument(skip(code))]
+    pub fn syntax_highlight_lines(extension: &str, code: &str) -> Result<Vec<String>> {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let syntax = ss.find_syntax_by_extension(extension).unwrap_or(ss.find_syntax_plain_text());
+        let mut highlighter = HighlightLines::new(syntax, theme);
+
+        let mut lines = Vec::new();
+        for line in LinesWithEndings::from(code) {
+            let regions = highlighter.highlight_line(line, &ss)?;
+            let html = styled_line_to_highlighted_html(&regions, IncludeBackground::No)?;
+            lines.push(html.replace('\n', ""));
+        }
+        Ok(lines)
+    }
+
     pub fn commit_to_object(repo: &Repository, commit: &Commit) -> Result<liquid::Object> {
         let hash = commit.id().to_string();
         let short_hash = hash[..7].to_owned();
         let author_name = commit.author().name().map(|s| s.to_owned());
         let author_email = commit.author().email().map(|s| s.to_owned());
         
-        let (diff_line, (added, removed)) = diff_info(repo, commit)?;
+        let (added, removed) = diff_stats(repo, commit)?;
 
         let (summary, description) = commit.message().unwrap().split_once('\n')
             .unwrap_or_else(|| (commit.message().unwrap(), ""));
@@ -86,26 +162,43 @@ pub mod templates {
                 "name": author_name,
                 "email": author_email,
             },
+            "date": format_time(commit.time()),
             "diff": {
                 "added": added,
                 "removed": removed,
-                "summary": diff_line,
             },
         }))
     }
 
-    pub fn full_diff(repo: &Repository, commit: &Commit, raw: bool) -> Result<String> {
-        let diff = makediff(repo, commit)?;
+    /// Which diff to show for a commit. Merges have one diff per parent and
+    /// default to the first.
+    #[derive(Debug, Clone, Copy)]
+    pub enum DiffView {
+        /// Against the parent with this index, counting from 0.
+        Parent(usize),
+        Combined,
+    }
+
+    pub fn full_diff(repo: &Repository, commit: &Commit, view: DiffView, config: &DiffConfig, raw: bool) -> Result<String> {
+        match view {
+            DiffView::Parent(parent) => render_diff(&makediff(repo, commit, parent, config)?, raw),
+            DiffView::Combined => {
+                let output = crate::combined_diff::combined_diff(repo, commit)?;
+                if raw {
+                    Ok(output)
+                } else {
+                    syntax_highlight("patch", &output)
+                }
+            }
+        }
+    }
+
+    /// Renders `diff` as a patch, highlighted unless `raw` is set.
+    pub fn render_diff(diff: &Diff, raw: bool) -> Result<String> {
         let mut output = String::new();
         diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
-            let c = match line.origin() {
-                '+' | '-' => {
-                    line.origin()
-                },
-                _ => ' ',
-            };
-            let line_str = std::str::from_utf8(line.content()).unwrap();
-            output.push_str(&format_line(line_str, c, raw));
+            let line_str = String::from_utf8_lossy(line.content());
+            output.push_str(&format_line(&line_str, line.origin(), raw));
             true
         })?;
         if raw {
@@ -115,45 +208,476 @@ pub mod templates {
         }
     }
 
-    fn format_line(line: &str, c: char, raw: bool) -> String {
-        if raw {
-            line.to_owned()
-        } else {
-            format!("{} {}", c, line)
+    /// Breaks `diff` into one object per file, with its status, line counts
+    /// and hunks, for the structured diff view.
+    pub fn diff_files(repo: &Repository, diff: &Diff) -> Result<Vec<liquid::Object>> {
+        let similarities = similarities(diff)?;
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let mut highlight_budget = MAX_DIFF_HIGHLIGHT_SIZE;
+        let mut files = Vec::new();
+        for index in 0..diff.deltas().len() {
+            let patch = match Patch::from_diff(diff, index)? {
+                Some(patch) => patch,
+                None => continue,
+            };
+            let delta = patch.delta();
+            let old_path = delta.old_file().path().map(|p| p.to_string_lossy().into_owned());
+            let new_path = delta.new_file().path().map(|p| p.to_string_lossy().into_owned());
+            let (_, added, removed) = patch.line_stats()?;
+
+            let status = match delta.status() {
+                Delta::Added => "added",
+                Delta::Deleted => "deleted",
+                Delta::Renamed => "renamed",
+                Delta::Copied => "copied",
+                Delta::Typechange => "type changed",
+                _ => "modified",
+            };
+            let (old_mode, new_mode) = (u32::from(delta.old_file().mode()), u32::from(delta.new_file().mode()));
+            let mode_change = (delta.status() == Delta::Modified && old_mode != new_mode)
+                .then(|| format!("{:o} → {:o}", old_mode, new_mode));
+
+            let mut hunks = Vec::new();
+            if !delta.flags().is_binary() {
+                let extension = new_path.as_ref().or(old_path.as_ref())
+                    .and_then(|p| std::path::Path::new(p).extension())
+                    .map(|e| e.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let syntax = ss.find_syntax_by_extension(&extension).unwrap_or(ss.find_syntax_plain_text());
+                // Submodules are commits of another repo, so their ids aren't
+                // blobs in this one. Once the diff has used up its budget, the
+                // rest of the files are shown as plain text.
+                let mut highlight = |file: git2::DiffFile| -> Result<Vec<Styles>> {
+                    if file.id().is_zero() || file.mode() == git2::FileMode::Commit {
+                        return Ok(Vec::new());
+                    }
+                    match repo.find_blob(file.id()) {
+                        Ok(blob) if blob.size() <= highlight_budget => {
+                            let styles = highlight_blob(&ss, syntax, theme, blob.content())?;
+                            if !styles.is_empty() {
+                                highlight_budget -= blob.size();
+                            }
+                            Ok(styles)
+                        }
+                        _ => Ok(Vec::new()),
+                    }
+                };
+                let old_styles = highlight(delta.old_file())?;
+                let new_styles = highlight(delta.new_file())?;
+                let styles_for = |styles: &[Styles], number: Option<u32>| number
+                    .and_then(|n| styles.get(n as usize - 1))
+                    .cloned()
+                    .unwrap_or_default();
+
+                for h in 0..patch.num_hunks() {
+                    let (hunk, line_count) = patch.hunk(h)?;
+                    let mut lines = Vec::with_capacity(line_count);
+                    for l in 0..line_count {
+                        let line = patch.line_in_hunk(h, l)?;
+                        let (kind, styles) = match line.origin() {
+                            '+' => ("added", styles_for(&new_styles, line.new_lineno())),
+                            '-' => ("removed", styles_for(&old_styles, line.old_lineno())),
+                            ' ' => ("context", styles_for(&new_styles, line.new_lineno())),
+                            // "\ No newline at end of file" and friends
+                            _ => ("meta", Vec::new()),
+                        };
+                        lines.push(DiffLine {
+                            kind,
+                            styles,
+                            old_number: line.old_lineno(),
+                            new_number: line.new_lineno(),
+                            text: String::from_utf8_lossy(line.content()).trim_end_matches(['\r', '\n']).to_owned(),
+                            changed: Vec::new(),
+                        });
+                    }
+                    for (left, right) in pairs(&lines) {
+                        if let (Some(left), Some(right)) = (left, right) {
+                            if left != right {
+                                if let Some((removed, added)) = word_diff(&lines[left].text, &lines[right].text) {
+                                    lines[left].changed = removed;
+                                    lines[right].changed = added;
+                                }
+                            }
+                        }
+                    }
+                    hunks.push(liquid::object!({
+                        "header": String::from_utf8_lossy(hunk.header()).trim_end().to_owned(),
+                        "rows": split_rows(&lines),
+                        "lines": lines.iter().map(DiffLine::to_object).collect::<Vec<_>>(),
+                    }));
+                }
+            }
+
+            let similarity = delta.new_file().path().and_then(|p| similarities.get(p)).copied();
+            files.push(liquid::object!({
+                "id": format!("file-{}", index),
+                "similarity": similarity,
+                "path": new_path.clone().or_else(|| old_path.clone()),
+                "old_path": old_path,
+                "status": status,
+                "mode_change": mode_change,
+                "binary": delta.flags().is_binary(),
+                "added": added,
+                "removed": removed,
+                "hunks": hunks,
+            }));
         }
+        Ok(files)
     }
 
-    fn diff_info(repo: &Repository, commit: &Commit) -> Result<(String, (i32, i32))> {
-        let diff = makediff(repo, commit)?;
-        let mut output = String::new();
-        let mut added = 0;
-        let mut removed = 0;
-        diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
-            match line.origin() {
-                ' ' | '+' | '-' => {
-                    write!(&mut output, "{}", line.origin()).unwrap()
-                },
-                _ => {}
+    type Ranges = Vec<Range<usize>>;
+    /// Syntax highlighting of a line, as byte ranges and their style.
+    type Styles = Vec<(Range<usize>, Style)>;
+
+    /// Files larger than this are shown in diffs without syntax highlighting.
+    const MAX_HIGHLIGHT_SIZE: usize = 512 * 1024;
+    /// Total size of the files highlighted for one diff, so a commit touching
+    /// many large files doesn't hold up the request for long.
+    const MAX_DIFF_HIGHLIGHT_SIZE: usize = 4 * 1024 * 1024;
+
+    /// Highlights a whole file and returns the styles of each line, so lines
+    /// in a hunk are coloured with the state (comments, strings) they have in
+    /// the full file.
+    fn highlight_blob(ss: &SyntaxSet, syntax: &SyntaxReference, theme: &Theme, content: &[u8]) -> Result<Vec<Styles>> {
+        if content.len() > MAX_HIGHLIGHT_SIZE || content.contains(&0) {
+            return Ok(Vec::new());
+        }
+        let text = String::from_utf8_lossy(content);
+        let mut highlighter = HighlightLines::new(syntax, theme);
+        let mut lines = Vec::new();
+        for line in LinesWithEndings::from(&text) {
+            let text_len = line.trim_end_matches(['\r', '\n']).len();
+            let mut offset = 0;
+            let mut styles = Vec::new();
+            for (style, region) in highlighter.highlight_line(line, ss)? {
+                let range = offset..(offset + region.len()).min(text_len);
+                offset += region.len();
+                if !range.is_empty() {
+                    styles.push((range, style));
+                }
             }
-            match line.origin() {
-                '-' => { removed += 1; }
-                '+' => { added += 1; }
-                _ => { }
+            lines.push(styles);
+        }
+        Ok(lines)
+    }
+
+    struct DiffLine {
+        kind: &'static str,
+        old_number: Option<u32>,
+        new_number: Option<u32>,
+        text: String,
+        styles: Styles,
+        /// Byte ranges of `text` that differ from the line it is paired with.
+        changed: Ranges,
+    }
+
+    impl DiffLine {
+        fn html(&self) -> String {
+            // Cut the line wherever either the syntax style or the word diff
+            // changes, then colour each piece and mark the changed ones.
+            let mut cuts = vec![0, self.text.len()];
+            for range in self.styles.iter().map(|(range, _)| range).chain(&self.changed) {
+                cuts.push(range.start);
+                cuts.push(range.end);
             }
-            true
-        })?;
-        Ok((output, (added, removed)))
+            cuts.sort_unstable();
+            cuts.dedup();
+
+            let mut html = String::new();
+            for piece in cuts.windows(2) {
+                let (start, end) = (piece[0], piece[1]);
+                let text = html_escape::encode_text(&self.text[start..end]);
+                let colored = match self.styles.iter().find(|(range, _)| range.contains(&start)) {
+                    Some((_, style)) => {
+                        let c = style.foreground;
+                        format!("<span style=\"color:#{:02x}{:02x}{:02x};\">{}</span>", c.r, c.g, c.b, text)
+                    }
+                    None => text.into_owned(),
+                };
+                if self.changed.iter().any(|range| range.contains(&start)) {
+                    html.push_str("<span class=\"diff-word\">");
+                    html.push_str(&colored);
+                    html.push_str("</span>");
+                } else {
+                    html.push_str(&colored);
+                }
+            }
+            html
+        }
+
+        fn to_object(&self) -> liquid::Object {
+            liquid::object!({
+                "kind": self.kind,
+                "old_number": self.old_number,
+                "new_number": self.new_number,
+                "html": self.html(),
+            })
+        }
     }
 
-    fn makediff<'repo>(repo: &'repo Repository, commit: &Commit) -> Result<Diff<'repo>> {
+    /// Pairs up the lines of a hunk by index: each run of removed lines is
+    /// matched with the run of added lines that follows it, and context lines
+    /// are paired with themselves.
+    fn pairs(lines: &[DiffLine]) -> Vec<(Option<usize>, Option<usize>)> {
+        let mut pairs = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            match lines[i].kind {
+                "removed" | "added" => {
+                    let removed_end = (i..lines.len()).find(|&j| lines[j].kind != "removed").unwrap_or(lines.len());
+                    let added_end = (removed_end..lines.len()).find(|&j| lines[j].kind != "added").unwrap_or(lines.len());
+                    let (removed, added) = (i..removed_end, removed_end..added_end);
+                    for k in 0..removed.len().max(added.len()) {
+                        pairs.push((Some(removed.start + k).filter(|j| removed.contains(j)), Some(added.start + k).filter(|j| added.contains(j))));
+                    }
+                    i = added_end;
+                }
+                _ => {
+                    pairs.push((Some(i), Some(i)));
+                    i += 1;
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Rows for the side-by-side layout.
+    fn split_rows(lines: &[DiffLine]) -> Vec<liquid::Object> {
+        pairs(lines).into_iter()
+            .map(|(left, right)| liquid::object!({
+                "left": left.map(|i| lines[i].to_object()),
+                "right": right.map(|i| lines[i].to_object()),
+            }))
+            .collect()
+    }
+
+    /// Splits a line into words, runs of whitespace and single punctuation
+    /// characters, so that word diffs line up with what people read as words.
+    fn tokenize(line: &str) -> Vec<&str> {
+        let class = |c: char| if c.is_alphanumeric() || c == '_' { 0 } else if c.is_whitespace() { 1 } else { 2 };
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let joins_next = match chars.peek() {
+                Some(&(_, next)) => class(c) != 2 && class(c) == class(next),
+                None => false,
+            };
+            if !joins_next {
+                let end = i + c.len_utf8();
+                tokens.push(&line[start..end]);
+                start = end;
+            }
+        }
+        tokens
+    }
+
+    /// Byte ranges that changed between a removed line and the added line
+    /// that replaced it. Returns `None` for lines too different (or too long)
+    /// for highlighting individual words to help.
+    fn word_diff(old: &str, new: &str) -> Option<(Ranges, Ranges)> {
+        const MAX_LINE_LENGTH: usize = 1000;
+        if old.len() > MAX_LINE_LENGTH || new.len() > MAX_LINE_LENGTH {
+            return None;
+        }
+        let (old_tokens, new_tokens) = (tokenize(old), tokenize(new));
+        let diff = similar::TextDiff::from_slices(&old_tokens, &new_tokens);
+        if diff.ratio() < 0.5 {
+            return None;
+        }
+
+        let offsets = |tokens: &[&str]| tokens.iter()
+            .scan(0, |offset, token| {
+                let start = *offset;
+                *offset += token.len();
+                Some(start..*offset)
+            })
+            .collect::<Vec<_>>();
+        let (old_offsets, new_offsets) = (offsets(&old_tokens), offsets(&new_tokens));
+
+        let (mut removed, mut added) = (Vec::new(), Vec::new());
+        for op in diff.ops() {
+            let (_, old_range, new_range) = op.as_tag_tuple();
+            if op.tag() == similar::DiffTag::Equal {
+                continue;
+            }
+            if !old_range.is_empty() {
+                removed.push(old_offsets[old_range.start].start..old_offsets[old_range.end - 1].end);
+            }
+            if !new_range.is_empty() {
+                added.push(new_offsets[new_range.start].start..new_offsets[new_range.end - 1].end);
+            }
+        }
+        Some((removed, added))
+    }
+
+    /// Prefixes a line of a patch with its origin. Raw output keeps the
+    /// format of `git diff`, so it can be applied as a patch; headers and the
+    /// "no newline" markers already carry their own text.
+    fn format_line(line: &str, origin: char, raw: bool) -> String {
+        match (origin, raw) {
+            ('+' | '-' | ' ', true) => format!("{}{}", origin, line),
+            (_, true) => line.to_owned(),
+            ('+' | '-', false) => format!("{} {}", origin, line),
+            (_, false) => format!("  {}", line),
+        }
+    }
+
+    /// Lines added and removed by `commit` against its first parent, for the
+    /// commit lists. Renames aren't detected, which keeps this cheap enough to
+    /// run for every commit on a page.
+    fn diff_stats(repo: &Repository, commit: &Commit) -> Result<(usize, usize)> {
+        let parent_tree = match commit.parent_count() {
+            0 => None,
+            _ => Some(commit.parent(0)?.tree()?),
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        let stats = diff.stats()?;
+        Ok((stats.insertions(), stats.deletions()))
+    }
+
+    /// Diffs `commit` against its parent with index `parent`, or against an
+    /// empty tree for root commits.
+    pub fn makediff<'repo>(repo: &'repo Repository, commit: &Commit, parent: usize, config: &DiffConfig) -> Result<Diff<'repo>> {
         let mut diffopts = DiffOptions::new();
-        let a = if commit.parents().len() == 1 {
-            let parent = commit.parent(0)?;
-            Some(parent.tree()?)
+        let a = if commit.parent_count() > 0 {
+            Some(commit.parent(parent)?.tree()?)
         } else {
             None
         };
         let b = commit.tree()?;
-        Ok(repo.diff_tree_to_tree(a.as_ref(), Some(&b), Some(&mut diffopts))?)
+        let mut diff = repo.diff_tree_to_tree(a.as_ref(), Some(&b), Some(&mut diffopts))?;
+        detect_renames(&mut diff, config)?;
+        Ok(diff)
+    }
+
+    /// Pairs up deleted and added files that are similar enough to count as
+    /// renames or copies.
+    pub fn detect_renames(diff: &mut Diff, config: &DiffConfig) -> Result<()> {
+        if !config.renames && !config.copies {
+            return Ok(());
+        }
+        let mut options = DiffFindOptions::new();
+        options.renames(config.renames)
+            .rename_threshold(config.rename_threshold)
+            .copies(config.copies)
+            .copy_threshold(config.copy_threshold);
+        diff.find_similar(Some(&mut options))?;
+        Ok(())
+    }
+
+    /// The similarity percentage of each renamed or copied file, keyed by its
+    /// new path. git2 doesn't expose it directly, so it is read from the
+    /// `similarity index` line of the patch headers.
+    fn similarities(diff: &Diff) -> Result<HashMap<PathBuf, u32>> {
+        let mut similarities = HashMap::new();
+        diff.print(DiffFormat::PatchHeader, |delta, _hunk, line| {
+            let header = String::from_utf8_lossy(line.content());
+            let similarity = header.lines()
+                .find_map(|l| l.strip_prefix("similarity index "))
+                .and_then(|s| s.trim_end_matches('%').parse().ok());
+            if let (Some(similarity), Some(path)) = (similarity, delta.new_file().path()) {
+                similarities.insert(path.to_owned(), similarity);
+            }
+            true
+        })?;
+        Ok(similarities)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn lines(kinds: &[&'static str]) -> Vec<DiffLine> {
+            kinds.iter()
+                .map(|&kind| DiffLine { kind, old_number: None, new_number: None, text: String::new(), styles: Vec::new(), changed: Vec::new() })
+                .collect()
+        }
+
+        #[test]
+        fn removed_lines_pair_with_the_added_lines_after_them() {
+            let hunk = lines(&["context", "removed", "removed", "added", "context"]);
+            assert_eq!(pairs(&hunk), vec![
+                (Some(0), Some(0)),
+                (Some(1), Some(3)),
+                (Some(2), None),
+                (Some(4), Some(4)),
+            ]);
+        }
+
+        #[test]
+        fn added_lines_without_removals_stand_alone() {
+            let hunk = lines(&["added", "added", "context", "removed"]);
+            assert_eq!(pairs(&hunk), vec![
+                (None, Some(0)),
+                (None, Some(1)),
+                (Some(2), Some(2)),
+                (Some(3), None),
+            ]);
+        }
+
+        #[test]
+        fn changed_words_are_marked() {
+            let (removed, added) = word_diff("let total = a + b;", "let total = a * b;").unwrap();
+            assert_eq!(removed, vec![14..15]);
+            assert_eq!(added, vec![14..15]);
+        }
+
+        #[test]
+        fn runs_of_changed_words_become_one_range() {
+            let (removed, added) = word_diff("call(first, second);", "call(first, other, second);").unwrap();
+            assert!(removed.is_empty());
+            assert_eq!(added, vec![12..19]);
+        }
+
+        #[test]
+        fn unrelated_or_long_lines_are_not_word_diffed() {
+            assert!(word_diff("fn main() {", "// nothing in common here").is_none());
+            let long = "x ".repeat(600);
+            assert!(word_diff(&long, &format!("{}y", long)).is_none());
+        }
+
+        #[test]
+        fn multibyte_characters_stay_whole() {
+            let (removed, added) = word_diff("naïve café", "naïve cafés").unwrap();
+            assert_eq!(removed, vec![7..12]);
+            assert_eq!(added, vec![7..13]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::compare_versions;
+
+    #[test]
+    fn numbers_compare_numerically() {
+        assert_eq!(compare_versions("v1.10", "v1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.3", "1.2.10"), Ordering::Less);
+        assert_eq!(compare_versions("v2", "v10"), Ordering::Less);
+    }
+
+    #[test]
+    fn leading_zeros_and_v_prefix_are_ignored() {
+        assert_eq!(compare_versions("v1.02", "1.2"), Ordering::Greater);
+        assert_eq!(compare_versions("1.007", "1.7"), Ordering::Greater);
+        assert_eq!(compare_versions("1.007", "1.8"), Ordering::Less);
+    }
+
+    #[test]
+    fn longer_versions_sort_after_their_prefix() {
+        assert_eq!(compare_versions("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.0-rc1", "1.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0", "1.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn text_compares_as_text() {
+        assert_eq!(compare_versions("1.0-alpha", "1.0-beta"), Ordering::Less);
+        assert_eq!(compare_versions("release-2", "release-10"), Ordering::Less);
     }
 }
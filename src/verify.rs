@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+
+use git2::{Oid, ObjectType, Repository, Odb};
+
+use crate::{config::Config, errors::{Result, GititError}};
+
+const STATUS_KEY: &str = "gitit.status";
+const STATUS_MESSAGE_KEY: &str = "gitit.statusMessage";
+
+/// Checks that every object in the mirror can be read and hashes to its id,
+/// and that every object reachable from a ref tip is present.
+#[tracing::instrument(skip(repo), fields(path = ?repo.path()))]
+pub(crate) fn verify_repo(repo: &Repository) -> Result<()> {
+    let odb = repo.odb()?;
+    let mut problems = Vec::new();
+
+    check_objects(&odb, &mut problems)?;
+    check_connectivity(repo, &odb, &mut problems)?;
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        for problem in &problems {
+            tracing::error!("{}", problem);
+        }
+        Err(GititError::VerificationFailed(format!("{} problem(s), first: {}", problems.len(), problems[0])))
+    }
+}
+
+fn check_objects(odb: &Odb, problems: &mut Vec<String>) -> Result<()> {
+    let mut ids = Vec::new();
+    odb.foreach(|id| {
+        ids.push(*id);
+        true
+    })?;
+
+    for id in ids {
+        match odb.read(id) {
+            Ok(object) => {
+                let actual = Oid::hash_object(object.kind(), object.data())?;
+                if actual != id {
+                    problems.push(format!("object {} hashes to {}", id, actual));
+                }
+            }
+            Err(e) => problems.push(format!("object {} is unreadable: {}", id, e.message())),
+        }
+    }
+
+    Ok(())
+}
+
+fn check_connectivity(repo: &Repository, odb: &Odb, problems: &mut Vec<String>) -> Result<()> {
+    let mut revwalk = repo.revwalk()?;
+    let mut seen = HashSet::new();
+    for rf in repo.references()? {
+        let rf = rf?;
+        let name = rf.name().unwrap_or("<invalid utf-8>").to_owned();
+        match rf.peel(ObjectType::Any) {
+            Ok(object) => match object.kind() {
+                Some(ObjectType::Commit) => revwalk.push(object.id())?,
+                Some(ObjectType::Tree) => check_tree(repo, odb, object.id(), &mut seen, problems),
+                _ => {}
+            },
+            Err(e) => problems.push(format!("ref {} does not point to a readable object: {}", name, e.message())),
+        }
+    }
+
+    for commit_id in revwalk {
+        let commit_id = match commit_id {
+            Ok(id) => id,
+            Err(e) => {
+                problems.push(format!("history is not connected: {}", e.message()));
+                break;
+            }
+        };
+        match repo.find_commit(commit_id) {
+            Ok(commit) => check_tree(repo, odb, commit.tree_id(), &mut seen, problems),
+            Err(e) => problems.push(format!("commit {} is missing: {}", commit_id, e.message())),
+        }
+    }
+
+    Ok(())
+}
+
+fn check_tree(repo: &Repository, odb: &Odb, tree_id: Oid, seen: &mut HashSet<Oid>, problems: &mut Vec<String>) {
+    if !seen.insert(tree_id) {
+        return;
+    }
+    let tree = match repo.find_tree(tree_id) {
+        Ok(tree) => tree,
+        Err(e) => {
+            problems.push(format!("tree {} is missing: {}", tree_id, e.message()));
+            return;
+        }
+    };
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(ObjectType::Tree) => check_tree(repo, odb, entry.id(), seen, problems),
+            Some(ObjectType::Blob) if seen.insert(entry.id()) && !odb.exists(entry.id()) => {
+                problems.push(format!("blob {} ({}) is missing", entry.id(), entry.name().unwrap_or("?")));
+            }
+            // Submodule commits live in another repository.
+            _ => {}
+        }
+    }
+}
+
+/// Stores the outcome of a verification run in the mirror's git config so the
+/// web server can refuse to serve it.
+pub(crate) fn record_status(repo: &Repository, result: &Result<()>) -> Result<()> {
+    let mut config = repo.config()?;
+    match result {
+        Ok(()) => {
+            config.set_str(STATUS_KEY, "ok")?;
+            config.remove(STATUS_MESSAGE_KEY).ok();
+        }
+        Err(e) => {
+            config.set_str(STATUS_KEY, "corrupt")?;
+            config.set_str(STATUS_MESSAGE_KEY, &e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_status(repo: &Repository) -> Result<()> {
+    let config = repo.config()?;
+    match config.get_string(STATUS_KEY) {
+        Ok(status) if status == "corrupt" => {
+            let message = config.get_string(STATUS_MESSAGE_KEY).unwrap_or_default();
+            Err(GititError::CorruptMirror(message))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn verify_repos(config: Config) -> Result<()> {
+    let mut failed = 0;
+    for (slug, repo_config) in &config.repos {
+        let path = config.repo_path(slug, repo_config);
+        if !path.exists() {
+            tracing::warn!("{} has not been cloned yet, skipping", slug);
+            continue;
+        }
+
+        let repo = Repository::open_bare(&path)?;
+        tracing::info!("Verifying {:?}...", &path);
+        let result = verify_repo(&repo);
+        record_status(&repo, &result)?;
+        match result {
+            Ok(()) => tracing::info!("{} is ok", slug),
+            Err(e) => {
+                tracing::error!("{} failed verification: {}", slug, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(GititError::VerificationFailed(format!("{} mirror(s) failed verification", failed)))
+    } else {
+        Ok(())
+    }
+}